@@ -1,16 +1,71 @@
-use esp_hal::{gpio::Level, rmt::PulseCode};
+// Float math for no_std, the tests link std which has it built in
+#[cfg(not(test))]
+use micromath::F32Ext;
 
 use crate::RGBPixel;
 
+/// Clock the RMT peripheral runs from (APB), passed to Rmt::new() in main.rs
+pub const RMT_SOURCE_HZ: u32 = 80_000_000;
+/// RMT channel clock divider, passed to with_clk_divider() in main.rs.
+/// All pulse timings are computed from it, so changing it here keeps them right.
+pub const RMT_CLOCK_DIVIDER: u8 = 1;
+/// Frequency of one RMT tick
+pub const RMT_TICK_HZ: u32 = RMT_SOURCE_HZ / RMT_CLOCK_DIVIDER as u32;
 
-// WS2812B timing (in RMT ticks at 80MHz clock with divider 1)
-// T0H = 0.4us = 32 ticks, T0L = 0.85us = 68 ticks
-// T1H = 0.8us = 64 ticks, T1L = 0.45us = 36 ticks
-// In my testing changing T1L to 48 ticks (0.6us) reduces flickering at the end of the strip
-const WS2812_T0H: u16 = 32;
-const WS2812_T0L: u16 = 56;
-const WS2812_T1H: u16 = 64;
-const WS2812_T1L: u16 = 48;
+/// Number of ticks at `tick_hz` closest to `ns` nanoseconds
+pub const fn ns_to_ticks(ns: u32, tick_hz: u32) -> u16 {
+  ((ns as u64 * tick_hz as u64 + 500_000_000) / 1_000_000_000) as u16
+}
+
+// WS2812B timing
+// Datasheet: T0H = 0.4us, T0L = 0.85us, T1H = 0.8us, T1L = 0.45us
+// In my testing changing T1L to 0.6us reduces flickering at the end of the strip
+const WS2812_T0H: u16 = ns_to_ticks(400, RMT_TICK_HZ);
+const WS2812_T0L: u16 = ns_to_ticks(700, RMT_TICK_HZ);
+const WS2812_T1H: u16 = ns_to_ticks(800, RMT_TICK_HZ);
+const WS2812_T1L: u16 = ns_to_ticks(600, RMT_TICK_HZ);
+
+// WS2811 in 400kHz (slow) mode, 2.5us per bit
+const WS2811_T0H: u16 = ns_to_ticks(500, RMT_TICK_HZ);
+const WS2811_T0L: u16 = ns_to_ticks(2000, RMT_TICK_HZ);
+const WS2811_T1H: u16 = ns_to_ticks(1200, RMT_TICK_HZ);
+const WS2811_T1L: u16 = ns_to_ticks(1300, RMT_TICK_HZ);
+
+// WS2815 (12V), 1.2us per bit
+const WS2815_T0H: u16 = ns_to_ticks(300, RMT_TICK_HZ);
+const WS2815_T0L: u16 = ns_to_ticks(900, RMT_TICK_HZ);
+const WS2815_T1H: u16 = ns_to_ticks(900, RMT_TICK_HZ);
+const WS2815_T1L: u16 = ns_to_ticks(300, RMT_TICK_HZ);
+
+// A pulse of 0 ticks would be read as the end marker
+const _: () = assert!(WS2815_T0H > 0, "RMT_CLOCK_DIVIDER too high for the pulse timings");
+// reset_pulses() works in whole microseconds
+const _: () = assert!(RMT_TICK_HZ.is_multiple_of(1_000_000), "RMT tick rate must be a whole number of MHz");
+
+/// Bit timing preset for the LED chip on the strip
+#[derive(Copy, Clone, PartialEq)]
+pub enum ChipTiming {
+  Ws2812b,
+  /// WS2811 at 400kHz, half the bit rate of the others
+  Ws2811,
+  Ws2815,
+}
+
+impl ChipTiming {
+  /// High and low time in RMT ticks of a 0 bit and a 1 bit: (T0H, T0L, T1H, T1L)
+  pub const fn ticks(self) -> (u16, u16, u16, u16) {
+    match self {
+      ChipTiming::Ws2812b => (WS2812_T0H, WS2812_T0L, WS2812_T1H, WS2812_T1L),
+      ChipTiming::Ws2811 => (WS2811_T0H, WS2811_T0L, WS2811_T1H, WS2811_T1L),
+      ChipTiming::Ws2815 => (WS2815_T0H, WS2815_T0L, WS2815_T1H, WS2815_T1L),
+    }
+  }
+}
+
+/// RMT ticks per microsecond
+pub const RMT_TICKS_PER_US: u32 = RMT_TICK_HZ / 1_000_000;
+/// Longest time one pulse pair can hold the line, each half is at most 15 bits
+pub const MAX_TICKS_PER_PULSE: u32 = 2 * 0x7FFF;
 
 pub fn hsv_to_rgb(h: u16, s: u8, v: u8) -> RGBPixel {
   // Normalize h to 0-359 range
@@ -29,7 +84,7 @@ pub fn hsv_to_rgb(h: u16, s: u8, v: u8) -> RGBPixel {
   // |h' mod 2 - 1| ranges from 0 to 1 as h_mod goes 0->60 or 60->0
   // For even sectors (0,2,4): h_mod goes 0->59, so factor = h_mod/60
   // For odd sectors (1,3,5): h_mod goes 0->59, so factor = 1 - h_mod/60
-  let x = if sector % 2 == 0 {
+  let x = if sector.is_multiple_of(2) {
     // Rising edge: x goes from 0 to c as h_mod goes 0 to 59
     (c * h_mod) / 60
   } else {
@@ -55,83 +110,729 @@ pub fn hsv_to_rgb(h: u16, s: u8, v: u8) -> RGBPixel {
   }
 }
 
-/// Convert a single byte to 8 PulseCodes for WS2812B
-fn byte_to_pulses(byte: u8, pulses: &mut [PulseCode]) {
-  for i in 0..8 {
+/// Convert RGB to (hue 0-359, saturation 0-255, value 0-255), the inverse of hsv_to_rgb.
+/// Grayscale pixels have no hue and return hue 0, saturation 0.
+pub fn rgb_to_hsv(pixel: &RGBPixel) -> (u16, u8, u8) {
+  // Integer division rounding to nearest, so round trips through hsv_to_rgb stay close
+  fn div_round(n: i32, d: i32) -> i32 {
+    if n >= 0 { (n + d / 2) / d } else { (n - d / 2) / d }
+  }
+
+  let r = pixel.r as i32;
+  let g = pixel.g as i32;
+  let b = pixel.b as i32;
+  let max = r.max(g).max(b);
+  let min = r.min(g).min(b);
+  let delta = max - min;
+
+  let v = max;
+  let s = if max == 0 { 0 } else { div_round(delta * 255, max) };
+
+  let h = if delta == 0 {
+    0 // Grayscale, hue is undefined
+  } else if max == r {
+    div_round(60 * (g - b), delta)
+  } else if max == g {
+    120 + div_round(60 * (b - r), delta)
+  } else {
+    240 + div_round(60 * (r - g), delta)
+  };
+
+  (h.rem_euclid(360) as u16, s as u8, v as u8)
+}
+
+/// Hue (0-359) of the LED at `index` for a rainbow spanning `cycles` full
+/// rainbows across `len` LEDs, shifted along by the animation `phase`
+pub fn rainbow_hue(index: usize, len: usize, cycles: f32, phase: f32) -> u16 {
+  // Position along strip * cycles * 360 degrees + animation offset
+  let hue = ((index as f32 / len as f32) * cycles * 360.0 + phase * 360.0) % 360.0;
+  hue as u16
+}
+
+/// Linearly interpolate between color stops at position `t` (0.0 to 1.0).
+/// `stops` must be sorted by position. Positions before the first stop or after
+/// the last stop take that stop's color. An empty palette returns off.
+pub fn palette_lookup(stops: &[(f32, RGBPixel)], t: f32) -> RGBPixel {
+  let (first, last) = match (stops.first(), stops.last()) {
+    (Some(first), Some(last)) => (first, last),
+    _ => return RGBPixel::off(),
+  };
+  if t <= first.0 {
+    return first.1;
+  }
+  if t >= last.0 {
+    return last.1;
+  }
+
+  for pair in stops.windows(2) {
+    let (start_pos, start) = pair[0];
+    let (end_pos, end) = pair[1];
+    if t > end_pos {
+      continue;
+    }
+    let span = end_pos - start_pos;
+    if span <= 0.0 {
+      return end;
+    }
+    // Fraction of the way from start to end
+    let f = (t - start_pos) / span;
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f + 0.5).clamp(0.0, 255.0) as u8;
+    return RGBPixel {
+      r: lerp(start.r, end.r),
+      g: lerp(start.g, end.g),
+      b: lerp(start.b, end.b),
+    };
+  }
+  last.1
+}
+
+/// Black-body-ish ramp for heat_to_color(): black, red, yellow-orange, white
+const HEAT_STOPS: [(f32, RGBPixel); 4] = [
+  (0.0, RGBPixel::off()),
+  (0.35, RGBPixel::red()),
+  (0.7, RGBPixel::new(255, 200, 0)),
+  (1.0, RGBPixel::white()),
+];
+
+/// Color of a flame at `heat` (0 cold, 255 hottest): black through red and orange to white.
+/// For fire effects, or as a gradient anywhere else. Mid heat (128) is a red-orange.
+pub fn heat_to_color(heat: u8) -> RGBPixel {
+  palette_lookup(&HEAT_STOPS, heat as f32 / 255.0)
+}
+
+/// Multiply each channel by `factor`. Channels that would go over 255 are clipped,
+/// which shifts bright colors towards white (e.g. orange towards yellow).
+/// With `preserve_hue` the factor is lowered instead so the brightest channel lands on 255,
+/// keeping the ratios between channels.
+/// `dither` (0.0 to 1.0) is added before rounding down, varying it between frames
+/// makes fractions show up on average instead of always rounding down.
+pub fn scale_rgb(r: u8, g: u8, b: u8, factor: f32, preserve_hue: bool, dither: f32) -> (u8, u8, u8) {
+  let mut factor = factor;
+  let max = r.max(g).max(b) as f32;
+  if preserve_hue && max * factor > 255.0 {
+    factor = 255.0 / max;
+  }
+  let scale = |x: u8| ((x as f32 * factor + dither).clamp(0.0, 255.0)) as u8;
+  (scale(r), scale(g), scale(b))
+}
+
+/// Color vision deficiency to remap colors for, so colors that would look the same are told apart
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorVisionMode {
+  None,
+  /// No red cones, red and green are confused
+  Protanopia,
+  /// No green cones, red and green are confused
+  Deuteranopia,
+  /// No blue cones, blue and green (and yellow and violet) are confused
+  Tritanopia,
+}
+
+impl ColorVisionMode {
+  /// Daltonization matrix (rows give R, G, B): the color is simulated as seen with the deficiency,
+  /// and what gets lost is shifted into the channels that are still seen.
+  /// Each row adds up to 1, so grays come out unchanged.
+  const fn matrix(self) -> Option<[[f32; 3]; 3]> {
+    match self {
+      ColorVisionMode::None => None,
+      ColorVisionMode::Protanopia => Some([[1.0, 0.0, 0.0], [-0.2549, 1.2549, 0.0], [0.3031, -0.5451, 1.242]]),
+      ColorVisionMode::Deuteranopia => Some([[1.0, 0.0, 0.0], [-0.4375, 1.4375, 0.0], [0.2625, -0.5625, 1.3]]),
+      ColorVisionMode::Tritanopia => Some([[1.05, -0.3825, 0.3325], [0.0, 1.2345, -0.2345], [0.0, 0.0, 1.0]]),
+    }
+  }
+}
+
+/// Remap a color to be easier to tell apart with the color vision deficiency `mode`.
+/// Returns the pixel unchanged for ColorVisionMode::None. Red becomes a pinkish red for protanopia
+/// and deuteranopia, moving it away from green.
+pub fn color_vision_remap(pixel: &RGBPixel, mode: ColorVisionMode) -> RGBPixel {
+  let Some(m) = mode.matrix() else {
+    return *pixel;
+  };
+  let (r, g, b) = (pixel.r as f32, pixel.g as f32, pixel.b as f32);
+  // Rounded, so float error can't turn 200 into 199 for grays
+  let row = |i: usize| ((m[i][0] * r + m[i][1] * g + m[i][2] * b + 0.5).clamp(0.0, 255.0)) as u8;
+  RGBPixel::new(row(0), row(1), row(2))
+}
+
+/// sin() over the first quarter cycle in 64 steps, the rest of the cycle is mirrored from this
+const QUARTER_SINE: [f32; 65] = [
+  0.0, 0.0245412, 0.0490677, 0.0735646, 0.0980171, 0.1224107, 0.1467305, 0.1709619,
+  0.1950903, 0.2191012, 0.2429802, 0.2667128, 0.2902847, 0.3136817, 0.3368899, 0.359895,
+  0.3826834, 0.4052413, 0.4275551, 0.4496113, 0.4713967, 0.4928982, 0.5141027, 0.5349976,
+  0.5555702, 0.5758082, 0.5956993, 0.6152316, 0.6343933, 0.6531728, 0.671559, 0.6895405,
+  core::f32::consts::FRAC_1_SQRT_2, 0.7242471, 0.7409511, 0.7572088, 0.7730105, 0.7883464, 0.8032075, 0.8175848,
+  0.8314696, 0.8448536, 0.8577286, 0.870087, 0.8819213, 0.8932243, 0.9039893, 0.9142098,
+  0.9238795, 0.9329928, 0.9415441, 0.9495282, 0.9569403, 0.9637761, 0.9700313, 0.9757021,
+  0.9807853, 0.9852776, 0.9891765, 0.9924795, 0.9951847, 0.9972905, 0.9987955, 0.9996988,
+  1.0,
+];
+
+/// sin(phase * TAU) from a lookup table with linear interpolation, `phase` is in cycles.
+/// Within 0.0001 of the real thing and a lot cheaper than micromath's sin() per LED per frame.
+pub fn fast_sin(phase: f32) -> f32 {
+  let x = phase.rem_euclid(1.0) * 256.0;
+  let index = x as usize;
+  let frac = x - index as f32;
+  // 256 steps per cycle, 64 per quarter
+  let lookup = |i: usize| {
+    let i = i % 256;
+    match i / 64 {
+      0 => QUARTER_SINE[i],
+      1 => QUARTER_SINE[128 - i],
+      2 => -QUARTER_SINE[i - 128],
+      _ => -QUARTER_SINE[256 - i],
+    }
+  };
+  let a = lookup(index);
+  a + (lookup(index + 1) - a) * frac
+}
+
+/// Curve used to ease in and out of a breath, see breath()
+#[derive(Copy, Clone, PartialEq)]
+pub enum Easing {
+  Sine,
+  /// Linear ramp up then down
+  Triangle,
+  QuadInOut,
+  Exponential,
+}
+
+impl Easing {
+  /// Map `t` from 0.0 to 1.0 onto the curve, 0.0 maps to 0.0 and 1.0 maps to 1.0
+  pub fn apply(self, t: f32) -> f32 {
+    match self {
+      Easing::Sine => ease_sine(t),
+      Easing::Triangle => t.clamp(0.0, 1.0),
+      Easing::QuadInOut => ease_quad_in_out(t),
+      Easing::Exponential => ease_exponential(t),
+    }
+  }
+}
+
+pub fn ease_sine(t: f32) -> f32 {
+  // cos(pi * t) is sin a quarter cycle ahead
+  0.5 - 0.5 * fast_sin(t.clamp(0.0, 1.0) / 2.0 + 0.25)
+}
+
+pub fn ease_quad_in_out(t: f32) -> f32 {
+  let t = t.clamp(0.0, 1.0);
+  if t < 0.5 {
+    2.0 * t * t
+  } else {
+    let u = 2.0 - 2.0 * t;
+    1.0 - u * u / 2.0
+  }
+}
+
+/// Stays dim for longer then rises sharply, like the sleep light on old laptops
+pub fn ease_exponential(t: f32) -> f32 {
+  let t = t.clamp(0.0, 1.0);
+  if t <= 0.0 {
+    0.0 // 2^-10 otherwise, which would never be fully off
+  } else {
+    2.0f32.powf(10.0 * t - 10.0)
+  }
+}
+
+/// Brightness (0.0 to 1.0) of one breath cycle at `phase`, rising then falling along `easing`.
+/// With Easing::Sine this is `0.5 + 0.5 * sin(phase * TAU)`, peaking at phase 0.25.
+pub fn breath(easing: Easing, phase: f32) -> f32 {
+  // Triangle wave from 0 up to 1 and back, lined up so the peak is at phase 0.25
+  let u = (phase + 0.25).rem_euclid(1.0);
+  let t = 1.0 - (2.0 * u - 1.0).abs();
+  easing.apply(t)
+}
+
+/// Map a perceived brightness (0.0 to 1.0, beyond 1.0 keeps rising) to the linear factor the channels are
+/// multiplied by, so equal steps look equally large. Uses the inverse of CIE 1976 lightness,
+/// e.g. 0.5 looks half as bright at ~0.18 of full power. Negative values are treated as 0.
+pub fn perceptual_to_linear(brightness: f32) -> f32 {
+  let l = brightness.max(0.0);
+  if l <= 0.08 {
+    l / 9.033
+  } else {
+    let t = (l + 0.16) / 1.16;
+    t * t * t
+  }
+}
+
+/// Lowest color temperature kelvin_to_rgb() accepts, lower values are clamped
+pub const MIN_KELVIN: u16 = 1000;
+/// Highest color temperature kelvin_to_rgb() accepts, higher values are clamped
+pub const MAX_KELVIN: u16 = 12000;
+
+/// Approximate the color of a blackbody at `kelvin` (clamped to MIN_KELVIN..=MAX_KELVIN).
+/// Uses Tanner Helland's curve fit, ~6600K is white, lower is warmer (orange), higher is bluer.
+pub fn kelvin_to_rgb(kelvin: u16) -> RGBPixel {
+  let temp = kelvin.clamp(MIN_KELVIN, MAX_KELVIN) as f32 / 100.0;
+  let r = if temp <= 66.0 {
+    255.0
+  } else {
+    329.698_73 * (temp - 60.0).powf(-0.133_204_76)
+  };
+  let g = if temp <= 66.0 {
+    99.470_8 * temp.ln() - 161.119_57
+  } else {
+    288.122_17 * (temp - 60.0).powf(-0.075_514_85)
+  };
+  let b = if temp >= 66.0 {
+    255.0
+  } else if temp <= 19.0 {
+    0.0
+  } else {
+    138.517_73 * (temp - 10.0).ln() - 305.044_8
+  };
+  RGBPixel {
+    r: r.clamp(0.0, 255.0) as u8,
+    g: g.clamp(0.0, 255.0) as u8,
+    b: b.clamp(0.0, 255.0) as u8,
+  }
+}
+
+/// Fill `table` with `255 * (i / 255) ^ gamma`, rounded, for looking up gamma corrected bytes.
+/// Too slow to run per pixel, so only compute it when gamma changes. A gamma of 1.0 is identity.
+pub fn gamma_table(gamma: f32, table: &mut [u8; 256]) {
+  for (i, entry) in table.iter_mut().enumerate() {
+    *entry = (255.0 * (i as f32 / 255.0).powf(gamma) + 0.5).clamp(0.0, 255.0) as u8;
+  }
+}
+
+/// Step a 1D cellular automaton one generation in place, wrapping around at the ends.
+/// `rule` is the Wolfram rule number: bit `n` is the next state of a cell whose
+/// (left, self, right) neighbourhood read as a 3 bit number is `n`. Rule 90 makes Sierpinski triangles,
+/// rule 30 looks random and rule 110 grows moving structures.
+pub fn life_step(cells: &mut [bool], rule: u8) {
+  let len = cells.len();
+  if len == 0 {
+    return;
+  }
+  // The old values of the cells that have already been overwritten
+  let first = cells[0];
+  let mut left = cells[len - 1];
+  for i in 0..len {
+    let center = cells[i];
+    let right = if i + 1 < len { cells[i + 1] } else { first };
+    let neighbourhood = (left as u8) << 2 | (center as u8) << 1 | right as u8;
+    cells[i] = (rule >> neighbourhood) & 1 != 0;
+    left = center;
+  }
+}
+
+/// xorshift32 pseudo-random number generator. `state` must not be 0.
+pub fn xorshift32(state: &mut u32) -> u32 {
+  let mut x = *state;
+  x ^= x << 13;
+  x ^= x >> 17;
+  x ^= x << 5;
+  *state = x;
+  x
+}
+
+/// A single pulse pair: `level1` for `length1` ticks, then `level2` for `length2` ticks.
+/// Implemented for esp_hal's PulseCode in hal.rs, and can be implemented
+/// by a plain struct to inspect pulse output without hardware.
+pub trait PulseEncoder {
+  fn new_pulse(level1: bool, length1: u16, level2: bool, length2: u16) -> Self;
+  /// The pulse that ends a transmission (both lengths 0)
+  fn end_marker() -> Self;
+}
+
+/// Same bit layout as esp_hal's PulseCode: length1 in bits 0-14, level1 in bit 15,
+/// length2 in bits 16-30 and level2 in bit 31. Used for pulse_data without the `hal` feature.
+impl PulseEncoder for u32 {
+  fn new_pulse(level1: bool, length1: u16, level2: bool, length2: u16) -> Self {
+    (length1 as u32 & 0x7FFF) | (level1 as u32) << 15 | (length2 as u32 & 0x7FFF) << 16 | (level2 as u32) << 31
+  }
+
+  fn end_marker() -> Self {
+    0
+  }
+}
+
+/// Convert a single byte to 8 pulses with the chip's timing.
+/// `inverted` swaps high and low, for driving the strip through an inverting level shifter.
+fn byte_to_pulses<P: PulseEncoder>(byte: u8, timing: ChipTiming, inverted: bool, pulses: &mut [P]) {
+  let (t0h, t0l, t1h, t1l) = timing.ticks();
+  for (i, pulse) in pulses[..8].iter_mut().enumerate() {
     let bit = (byte >> (7 - i)) & 1;
-    pulses[i] = if bit == 1 {
-      PulseCode::new(Level::High, WS2812_T1H, Level::Low, WS2812_T1L)
+    *pulse = if bit == 1 {
+      P::new_pulse(!inverted, t1h, inverted, t1l)
     } else {
-      PulseCode::new(Level::High, WS2812_T0H, Level::Low, WS2812_T0L)
+      P::new_pulse(!inverted, t0h, inverted, t0l)
     };
   }
 }
 
-/// Convert RGB color to WS2812B pulse data (GRB order)
-pub fn rgb_to_pulses(pixel: &RGBPixel, pulses: &mut [PulseCode]) {
-  byte_to_pulses(pixel.g, &mut pulses[0..8]);
-  byte_to_pulses(pixel.r, &mut pulses[8..16]);
-  byte_to_pulses(pixel.b, &mut pulses[16..24]);
+/// Fill `pulses` with low pulses (high if `inverted`) lasting `reset_us` microseconds in total,
+/// to extend the reset gap. Returns the number of pulses used, limited by the length of `pulses`.
+pub fn reset_pulses<P: PulseEncoder>(reset_us: u16, inverted: bool, pulses: &mut [P]) -> usize {
+  let mut ticks = reset_us as u32 * RMT_TICKS_PER_US;
+  let mut count = 0;
+  while ticks > 0 && count < pulses.len() {
+    let pulse_ticks = ticks.min(MAX_TICKS_PER_PULSE);
+    // Neither half may be 0 ticks, that would be read as an end marker
+    let first = (pulse_ticks / 2).max(1);
+    let second = (pulse_ticks - first).max(1);
+    pulses[count] = P::new_pulse(inverted, first as u16, inverted, second as u16);
+    ticks -= pulse_ticks;
+    count += 1;
+  }
+  count
+}
+
+/// Byte layout of the LEDs in a segment of the strip
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorFormat {
+  /// WS2812, 24 bits per LED
+  Grb,
+  /// SK6812 RGBW, 32 bits per LED
+  Grbw,
 }
 
-/// Helper function to format and print elapsed time to USB serial
-/// Formats time as milliseconds with microsecond precision (e.g., "12.345ms")
-pub fn print_elapsed_time<'a, Dm: esp_hal::DriverMode>(serial: &mut esp_hal::usb_serial_jtag::UsbSerialJtagTx<'a, Dm>, elapsed: esp_hal::time::Duration) {
-  let micros = elapsed.as_micros();
-  let millis = micros / 1000;
-  let frac = micros % 1000;
+impl ColorFormat {
+  /// Number of pulses (bits) sent per LED
+  pub const fn pulses_per_led(self) -> usize {
+    match self {
+      ColorFormat::Grb => 24,
+      ColorFormat::Grbw => 32,
+    }
+  }
+}
+
+/// Bytes sent to one LED in `format`, in the order they are sent (G, R, B, then W for GRBW).
+/// The last byte is unused for GRB. For GRBW the part of the color shared by all three channels
+/// (the lowest one) is moved to the white channel.
+pub fn color_bytes(pixel: &RGBPixel, format: ColorFormat) -> [u8; 4] {
+  match format {
+    ColorFormat::Grb => [pixel.g, pixel.r, pixel.b, 0],
+    ColorFormat::Grbw => {
+      let white = pixel.r.min(pixel.g).min(pixel.b);
+      [pixel.g - white, pixel.r - white, pixel.b - white, white]
+    }
+  }
+}
 
-  // Manual formatting in no_std environment
-  let mut buffer = [0u8; 64];
-  let mut pos = 0;
+/// Convert every byte to 8 pulses with the chip's timing, `pulses` must hold 8 per byte
+pub fn bytes_to_pulses<P: PulseEncoder>(bytes: &[u8], timing: ChipTiming, inverted: bool, pulses: &mut [P]) {
+  for (byte, pulses) in bytes.iter().zip(pulses.chunks_exact_mut(8)) {
+    byte_to_pulses(*byte, timing, inverted, pulses);
+  }
+}
 
-  // Write "Frame time: "
-  let prefix = b"Frame time: ";
-  buffer[pos..pos + prefix.len()].copy_from_slice(prefix);
-  pos += prefix.len();
+/// SPI clock for the SPI transmit path, 3 SPI bits per LED bit makes each bit 1.25us like the RMT pulses
+pub const SPI_FREQUENCY_KHZ: u32 = 2400;
+/// SPI bytes sent per LED byte, see byte_to_spi()
+pub const SPI_BYTES_PER_BYTE: usize = 3;
+/// Low time always sent after the LEDs over SPI, long enough for any WS2812 to latch.
+/// The next DMA transfer can start right away, so the line isn't left idle between frames like with the RMT
+pub const SPI_MIN_RESET_US: u32 = 300;
 
-  // Format milliseconds
-  pos += format_u64(millis, &mut buffer[pos..]);
+/// Encode `byte` as 24 SPI bits sent MSB first at SPI_FREQUENCY_KHZ: 0b110 for a 1 (833ns high, 417ns low)
+/// and 0b100 for a 0 (417ns high, 833ns low). Always WS2812 timing, ChipTiming only applies to the RMT.
+pub fn byte_to_spi(byte: u8) -> [u8; SPI_BYTES_PER_BYTE] {
+  let bits = (0..8).rev().fold(0u32, |bits, i| (bits << 3) | if (byte >> i) & 1 == 1 { 0b110 } else { 0b100 });
+  let [_, first, second, third] = bits.to_be_bytes();
+  [first, second, third]
+}
 
-  // Add decimal point
-  buffer[pos] = b'.';
-  pos += 1;
+/// Convert every byte to SPI_BYTES_PER_BYTE SPI bytes, `spi` must hold that many per byte
+pub fn bytes_to_spi(bytes: &[u8], spi: &mut [u8]) {
+  for (byte, spi) in bytes.iter().zip(spi.chunks_exact_mut(SPI_BYTES_PER_BYTE)) {
+    spi.copy_from_slice(&byte_to_spi(*byte));
+  }
+}
 
-  // Format microseconds (3 digits with leading zeros)
-  buffer[pos] = b'0' + ((frac / 100) % 10) as u8;
-  buffer[pos + 1] = b'0' + ((frac / 10) % 10) as u8;
-  buffer[pos + 2] = b'0' + (frac % 10) as u8;
-  pos += 3;
+/// Number of low SPI bytes to hold the line low for SPI_MIN_RESET_US plus `reset_us`
+pub const fn spi_reset_bytes(reset_us: u16) -> usize {
+  ((SPI_MIN_RESET_US + reset_us as u32) * SPI_FREQUENCY_KHZ).div_ceil(8 * 1000) as usize
+}
 
-  // Add "ms\n"
-  buffer[pos] = b'm';
-  buffer[pos + 1] = b's';
-  buffer[pos + 2] = b'\n';
-  pos += 3;
+/// Convert RGB color to SK6812 RGBW pulse data (GRBW order), see color_bytes()
+pub fn rgbw_to_pulses<P: PulseEncoder>(pixel: &RGBPixel, timing: ChipTiming, inverted: bool, pulses: &mut [P]) {
+  bytes_to_pulses(&color_bytes(pixel, ColorFormat::Grbw), timing, inverted, &mut pulses[0..32]);
+}
 
-  let _ = serial.write(&buffer[..pos]);
+/// Convert RGB color to WS2812 pulse data (GRB order) with the chip's timing, with high and low swapped if `inverted`
+pub fn rgb_to_pulses<P: PulseEncoder>(pixel: &RGBPixel, timing: ChipTiming, inverted: bool, pulses: &mut [P]) {
+  bytes_to_pulses(&color_bytes(pixel, ColorFormat::Grb)[..3], timing, inverted, &mut pulses[0..24]);
 }
 
-/// Helper to format a u64 into a byte buffer, returns number of bytes written
-fn format_u64(mut n: u64, buffer: &mut [u8]) -> usize {
-  if n == 0 {
-    buffer[0] = b'0';
-    return 1;
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rgb(pixel: RGBPixel) -> (u8, u8, u8) {
+    (pixel.r, pixel.g, pixel.b)
+  }
+
+  /// Captures what the encoders produce, field by field
+  #[derive(Copy, Clone, Default, PartialEq, Debug)]
+  struct MockPulse {
+    l1: bool,
+    t1: u16,
+    l2: bool,
+    t2: u16,
   }
 
-  let mut temp = [0u8; 20]; // Max u64 is 20 digits
-  let mut i = 0;
+  impl PulseEncoder for MockPulse {
+    fn new_pulse(level1: bool, length1: u16, level2: bool, length2: u16) -> Self {
+      MockPulse { l1: level1, t1: length1, l2: level2, t2: length2 }
+    }
 
-  while n > 0 {
-    temp[i] = b'0' + (n % 10) as u8;
-    n /= 10;
-    i += 1;
+    fn end_marker() -> Self {
+      MockPulse::default()
+    }
   }
 
-  // Reverse into buffer
-  for j in 0..i {
-    buffer[j] = temp[i - 1 - j];
+  fn bit_pulses(inverted: bool) -> (MockPulse, MockPulse) {
+    let (t0h, t0l, t1h, t1l) = ChipTiming::Ws2812b.ticks();
+    (
+      MockPulse::new_pulse(!inverted, t0h, inverted, t0l),
+      MockPulse::new_pulse(!inverted, t1h, inverted, t1l),
+    )
   }
 
-  i
-}
\ No newline at end of file
+  #[test]
+  fn fast_sin_matches_sin_over_a_period() {
+    let mut max_error: f32 = 0.0;
+    for i in 0..=10_000 {
+      let phase = i as f32 / 10_000.0;
+      max_error = max_error.max((fast_sin(phase) - (phase * core::f32::consts::TAU).sin()).abs());
+    }
+    assert!(max_error < 0.0001, "max error {max_error}");
+    // Whole cycles either way give the same value
+    assert!((fast_sin(0.3) - fast_sin(2.3)).abs() < 0.000_001);
+    assert!((fast_sin(-0.25) + 1.0).abs() < 0.0001);
+  }
+
+  #[test]
+  fn pulse_ticks_match_the_hard_coded_ones() {
+    // Both the C3 and S3 clock the RMT at 80MHz, these were hard-coded for divider 1
+    assert_eq!(RMT_TICK_HZ, 80_000_000);
+    assert_eq!(ChipTiming::Ws2812b.ticks(), (32, 56, 64, 48));
+    assert_eq!(ChipTiming::Ws2811.ticks(), (40, 160, 96, 104));
+    assert_eq!(ChipTiming::Ws2815.ticks(), (24, 72, 72, 24));
+    // Reset pulses are counted in these
+    assert_eq!(RMT_TICKS_PER_US, 80);
+  }
+
+  #[test]
+  fn ns_to_ticks_follows_the_divider() {
+    let divider_2 = RMT_SOURCE_HZ / 2;
+    assert_eq!([400, 700, 800, 600].map(|ns| ns_to_ticks(ns, divider_2)), [16, 28, 32, 24]);
+    // Rounds to the nearest tick
+    assert_eq!(ns_to_ticks(310, divider_2), 12);
+    assert_eq!(ns_to_ticks(320, divider_2), 13);
+  }
+
+  #[test]
+  fn bytes_to_pulses_all_ones_and_zeros() {
+    let (zero, one) = bit_pulses(false);
+    let mut pulses = [MockPulse::default(); 16];
+    bytes_to_pulses(&[0xFF, 0x00], ChipTiming::Ws2812b, false, &mut pulses);
+    assert_eq!(pulses[..8], [one; 8]);
+    assert_eq!(pulses[8..], [zero; 8]);
+    // T1 is high for longer than T0
+    assert!(one.t1 > zero.t1 && one.l1 && !one.l2);
+  }
+
+  #[test]
+  fn bytes_to_pulses_msb_first() {
+    let (zero, one) = bit_pulses(false);
+    let mut pulses = [MockPulse::default(); 8];
+    bytes_to_pulses(&[0b1010_0001], ChipTiming::Ws2812b, false, &mut pulses);
+    assert_eq!(pulses, [one, zero, one, zero, zero, zero, zero, one]);
+  }
+
+  #[test]
+  fn bytes_to_pulses_inverted() {
+    let (zero, one) = bit_pulses(true);
+    let mut pulses = [MockPulse::default(); 8];
+    bytes_to_pulses(&[0xF0], ChipTiming::Ws2812b, true, &mut pulses);
+    assert_eq!(pulses, [one, one, one, one, zero, zero, zero, zero]);
+    assert!(!one.l1 && one.l2);
+  }
+
+  #[test]
+  fn bytes_to_spi_three_bits_per_bit() {
+    let mut spi = [0; 9];
+    bytes_to_spi(&[0xFF, 0x00, 0b1010_0001], &mut spi);
+    // 110 for each 1, 100 for each 0, MSB first
+    assert_eq!(spi[..3], [0b1101_1011, 0b0110_1101, 0b1011_0110]);
+    assert_eq!(spi[3..6], [0b1001_0010, 0b0100_1001, 0b0010_0100]);
+    assert_eq!(spi[6..], [0b1101_0011, 0b0100_1001, 0b0010_0110]);
+  }
+
+  #[test]
+  fn spi_reset_covers_the_reset_time() {
+    // 3.33us per byte at 2.4MHz
+    assert_eq!(spi_reset_bytes(0), 90);
+    assert_eq!(spi_reset_bytes(1), 91);
+    assert!(spi_reset_bytes(100) as u32 * 8 * 1000 >= 400 * SPI_FREQUENCY_KHZ);
+  }
+
+  #[test]
+  fn rgb_to_pulses_sends_grb() {
+    let (zero, one) = bit_pulses(false);
+    let mut pulses = [MockPulse::default(); 24];
+    rgb_to_pulses(&RGBPixel::new(0x00, 0xFF, 0x00), ChipTiming::Ws2812b, false, &mut pulses);
+    assert_eq!(pulses[..8], [one; 8]);
+    assert_eq!(pulses[8..], [zero; 16]);
+  }
+
+  #[test]
+  fn reset_pulse_tail() {
+    let mut pulses = [MockPulse::default(); 4];
+    // 300us at 80 ticks per us needs 24000 ticks, which fits in one pulse
+    let count = reset_pulses(300, false, &mut pulses);
+    assert_eq!(count, 1);
+    assert_eq!(pulses[0], MockPulse::new_pulse(false, 12000, false, 12000));
+    assert_eq!(pulses[1], MockPulse::end_marker());
+
+    // 1000us needs 80000 ticks, more than one pulse holds, all low and none of them 0 ticks
+    let count = reset_pulses(1000, false, &mut pulses);
+    assert_eq!(count, 2);
+    let total: u32 = pulses[..count].iter().map(|p| p.t1 as u32 + p.t2 as u32).sum();
+    assert_eq!(total, 1000 * RMT_TICKS_PER_US);
+    assert!(pulses[..count].iter().all(|p| !p.l1 && !p.l2 && p.t1 > 0 && p.t2 > 0));
+
+    // Inverted holds the line high, and the gap is cut short when out of pulses
+    let count = reset_pulses(5000, true, &mut pulses[..2]);
+    assert_eq!(count, 2);
+    assert!(pulses[..count].iter().all(|p| p.l1 && p.l2));
+  }
+
+  #[test]
+  fn u32_pulse_matches_pulse_code_layout() {
+    assert_eq!(u32::new_pulse(true, 0x7FFF, false, 1), 0x0001_FFFF);
+    assert_eq!(u32::new_pulse(false, 1, true, 2), 0x8002_0001);
+    assert_eq!(u32::end_marker(), 0);
+  }
+
+  #[test]
+  fn hsv_to_rgb_primaries() {
+    assert_eq!(rgb(hsv_to_rgb(0, 255, 255)), (255, 0, 0));
+    assert_eq!(rgb(hsv_to_rgb(60, 255, 255)), (255, 255, 0));
+    assert_eq!(rgb(hsv_to_rgb(120, 255, 255)), (0, 255, 0));
+    assert_eq!(rgb(hsv_to_rgb(180, 255, 255)), (0, 255, 255));
+    assert_eq!(rgb(hsv_to_rgb(240, 255, 255)), (0, 0, 255));
+    assert_eq!(rgb(hsv_to_rgb(300, 255, 255)), (255, 0, 255));
+  }
+
+  #[test]
+  fn hsv_to_rgb_wraps_hue() {
+    assert_eq!(rgb(hsv_to_rgb(360, 255, 255)), rgb(hsv_to_rgb(0, 255, 255)));
+    assert_eq!(rgb(hsv_to_rgb(480, 255, 255)), rgb(hsv_to_rgb(120, 255, 255)));
+  }
+
+  #[test]
+  fn hsv_to_rgb_saturation_and_value() {
+    assert_eq!(rgb(hsv_to_rgb(200, 0, 128)), (128, 128, 128));
+    assert_eq!(rgb(hsv_to_rgb(200, 255, 0)), (0, 0, 0));
+    assert_eq!(rgb(hsv_to_rgb(0, 255, 100)), (100, 0, 0));
+  }
+
+  #[test]
+  fn rgb_to_hsv_round_trip() {
+    for &(r, g, b) in &[(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 128, 0), (10, 200, 90)] {
+      let (h, s, v) = rgb_to_hsv(&RGBPixel::new(r, g, b));
+      let (r2, g2, b2) = rgb(hsv_to_rgb(h, s, v));
+      assert!(r.abs_diff(r2) <= 2 && g.abs_diff(g2) <= 2 && b.abs_diff(b2) <= 2, "({r}, {g}, {b}) came back as ({r2}, {g2}, {b2})");
+    }
+  }
+
+  #[test]
+  fn palette_lookup_interpolates_and_clamps() {
+    let stops = [
+      (0.25, RGBPixel::new(0, 0, 0)),
+      (0.5, RGBPixel::new(200, 100, 0)),
+      (1.0, RGBPixel::new(0, 100, 255)),
+    ];
+    // Exactly on a stop, and halfway between two
+    assert_eq!(rgb(palette_lookup(&stops, 0.5)), (200, 100, 0));
+    assert_eq!(rgb(palette_lookup(&stops, 0.375)), (100, 50, 0));
+    assert_eq!(rgb(palette_lookup(&stops, 0.75)), (100, 100, 128));
+    // Past either end takes the end stop
+    assert_eq!(rgb(palette_lookup(&stops, 0.0)), (0, 0, 0));
+    assert_eq!(rgb(palette_lookup(&stops, 1.5)), (0, 100, 255));
+    // One stop is that color everywhere, no stops is off
+    let single = [(0.5, RGBPixel::new(1, 2, 3))];
+    assert_eq!(rgb(palette_lookup(&single, 0.0)), (1, 2, 3));
+    assert_eq!(rgb(palette_lookup(&single, 1.0)), (1, 2, 3));
+    assert_eq!(rgb(palette_lookup(&[], 0.5)), (0, 0, 0));
+  }
+
+  #[test]
+  fn kelvin_to_rgb_white_and_warm() {
+    // Daylight is close to neutral white
+    let (r, g, b) = rgb(kelvin_to_rgb(6500));
+    assert!(r >= 245 && g >= 245 && b >= 245, "{:?}", (r, g, b));
+    // Candlelight is orange, red over green over blue
+    let (r, g, b) = rgb(kelvin_to_rgb(2000));
+    assert_eq!(r, 255);
+    assert!(g > 100 && g < 160 && b < 40, "{:?}", (r, g, b));
+    // Out of range temperatures are clamped
+    assert!(kelvin_to_rgb(0) == kelvin_to_rgb(MIN_KELVIN));
+    assert!(kelvin_to_rgb(u16::MAX) == kelvin_to_rgb(MAX_KELVIN));
+  }
+
+  #[test]
+  fn easing_curves_map_the_endpoints() {
+    for easing in [Easing::Sine, Easing::Triangle, Easing::QuadInOut, Easing::Exponential] {
+      assert!(easing.apply(0.0).abs() < 1e-3);
+      assert!((easing.apply(1.0) - 1.0).abs() < 1e-3);
+      // Out of range input is clamped
+      assert!(easing.apply(-1.0).abs() < 1e-3);
+      assert!((easing.apply(2.0) - 1.0).abs() < 1e-3);
+      // A breath peaks at phase 0.25 and is fully off at 0.75
+      assert!((breath(easing, 0.25) - 1.0).abs() < 1e-3);
+      assert!(breath(easing, 0.75).abs() < 1e-3);
+    }
+    // The symmetric curves are halfway at the midpoint, exponential is still dim
+    for easing in [Easing::Sine, Easing::Triangle, Easing::QuadInOut] {
+      assert!((easing.apply(0.5) - 0.5).abs() < 1e-3);
+    }
+    assert!(Easing::Exponential.apply(0.5) < 0.05);
+  }
+
+  #[test]
+  fn scale_rgb_keeps_over_bright_orange_orange() {
+    // Clipping red at 255 while green keeps rising turns orange yellow
+    assert_eq!(scale_rgb(200, 100, 0, 1.5, false, 0.0), (255, 150, 0));
+    // Preserving the hue keeps green at half of red
+    assert_eq!(scale_rgb(200, 100, 0, 1.5, true, 0.0), (255, 127, 0));
+    // Nothing to preserve when no channel goes over 255
+    assert_eq!(scale_rgb(200, 100, 0, 0.5, true, 0.0), scale_rgb(200, 100, 0, 0.5, false, 0.0));
+  }
+
+  #[test]
+  fn color_vision_remap_keeps_grays_and_moves_red() {
+    let modes = [ColorVisionMode::None, ColorVisionMode::Protanopia, ColorVisionMode::Deuteranopia, ColorVisionMode::Tritanopia];
+    for mode in modes {
+      for v in 0..=255 {
+        assert_eq!(rgb(color_vision_remap(&RGBPixel::new(v, v, v), mode)), (v, v, v));
+      }
+    }
+    let red = RGBPixel::new(255, 0, 0);
+    assert_eq!(rgb(color_vision_remap(&red, ColorVisionMode::None)), (255, 0, 0));
+    // Blue is mixed in for the red-green deficiencies, tritanopia can already tell red apart
+    assert_eq!(rgb(color_vision_remap(&red, ColorVisionMode::Protanopia)), (255, 0, 77));
+    assert_eq!(rgb(color_vision_remap(&red, ColorVisionMode::Deuteranopia)), (255, 0, 67));
+    assert_eq!(rgb(color_vision_remap(&red, ColorVisionMode::Tritanopia)), (255, 0, 0));
+  }
+
+  #[test]
+  fn heat_to_color_goes_from_black_to_white() {
+    assert_eq!(rgb(heat_to_color(0)), (0, 0, 0));
+    // Red-orange: red leads, some green, no blue
+    let (r, g, b) = rgb(heat_to_color(128));
+    assert!(r >= 200 && g > 0 && g < r / 2 && b == 0, "{:?}", (r, g, b));
+    assert_eq!(rgb(heat_to_color(255)), (255, 255, 255));
+    // Never gets darker as it heats up
+    for heat in 1..=255 {
+      let (cooler, hotter) = (heat_to_color(heat - 1), heat_to_color(heat));
+      assert!(hotter.r >= cooler.r && hotter.g >= cooler.g && hotter.b >= cooler.b);
+    }
+  }
+}
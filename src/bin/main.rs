@@ -13,18 +13,28 @@ compile_error!("Select a target with --features esp32c3 or --features esp32s3");
 compile_error!("Only one target feature may be enabled at a time");
 
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use critical_section::Mutex;
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions;
 use esp_hal::clock::CpuClock;
 use esp_hal::delay::Delay;
-use esp_hal::gpio::Level;
 use esp_hal::{handler, main};
-use esp_hal::rmt::{PulseCode, Rmt, TxChannelConfig, TxChannelCreator};
-use esp_hal::time::{Instant, Rate};
-use esp_hal::usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagRx};
+use esp_hal::rmt::{PulseCode, Rmt};
+use esp_hal::time::{Duration, Instant, Rate};
+use esp_hal::timer::PeriodicTimer;
+use esp_hal::timer::timg::{MwdtStage, MwdtStageAction, TimerGroup};
+use esp_hal::usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagRx, UsbSerialJtagTx};
+use esp_storage::FlashStorage;
+use heapless::Deque;
 use heapless::spsc::{Producer, Queue};
-use rgb_led::{LEDStrip, NUM_LEDS, SerialParser};
+use static_cell::StaticCell;
+use rgb_led::{
+  CONFIG_SNAPSHOT_LEN, LEDStrip, MAX_FRAME_LEN, PARSER_BUFFER_LEN, PIXEL_DUMP_ACTION, RENDER_BUFFER_LEN, RMT_SOURCE_HZ,
+  SerialParser, Status, StripConfig, encode_perf, setup_strip,
+};
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -35,10 +45,67 @@ fn panic(_: &core::panic::PanicInfo) -> ! {
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
+/// Flash red, green, blue then white on boot to check wiring and color order, off by default.
+/// The self test setting (0x08) runs the same thing on demand
+const SELF_TEST_ON_BOOT: bool = false;
+
+/// Number of strips, each on its own RMT channel (see the channel setup in main()).
+/// The ESP32-C3 has 2 RMT TX channels, the ESP32-S3 has 4.
+const NUM_STRIPS: usize = 2;
+/// Action choosing which strip the following commands go to, 1 byte strip index
+const SELECT_STRIP_ACTION: u8 = 0x0E;
+
+/// How often to check whether the config changed and save it to flash.
+/// Flash sectors survive ~100k erases, and nothing is written unless the config changed,
+/// so even constant changes wear out the sector after ~70 days of saving every minute.
+const CONFIG_SAVE_INTERVAL_MS: u64 = 60_000;
+/// Label of the data partition the configs are saved in, one sector per strip (see partitions.csv)
+const CONFIG_PARTITION_LABEL: &str = "ledconfig";
+
+/// Drop a partly received frame after this long without new bytes (e.g. the host crashed mid-frame).
+/// A max length frame arrives in a few ms over USB, so this only catches frames that are never finished.
+const PARTIAL_FRAME_TIMEOUT_MS: u64 = 200;
+
+/// Send a perf report (measured fps and longest frame time) every this many loops, 0 to never send one
+const PERF_REPORT_FRAMES: u32 = 500;
+
+/// Reset the chip if the main loop doesn't come around for this long, e.g. stuck on a USB write.
+/// The strip comes back with the last saved config. Keep it well above the longest frame (1s at 1 fps)
+/// plus a config save to flash. 0 turns the watchdog off.
+const WATCHDOG_TIMEOUT_MS: u64 = 3000;
+
+/// How the main loop waits for the next frame
+#[derive(PartialEq)]
+enum Pacing {
+  /// Busy wait for what's left of the frame after measuring how long it took. Needs no timer
+  BusyWait,
+  /// A periodic timer interrupt (TIMG0) marks each frame as due. The ESP32-C3 sleeps in between,
+  /// the ESP32-S3 spins (see wait_for_interrupt())
+  Timer,
+}
+const PACING: Pacing = Pacing::BusyWait;
+
 static USB_SERIAL_RX: Mutex<RefCell<Option<UsbSerialJtagRx<'static, esp_hal::Blocking>>>> =
   Mutex::new(RefCell::new(None));
 
-static mut USB_QUEUE: Queue<u8, { 16*1024 }> = Queue::new();
+/// Bytes the USB ISR can buffer before the main loop reads them, ~15 max length frames.
+/// Lower to save RAM, raise to survive longer bursts. Bytes arriving while it is full are dropped.
+const USB_QUEUE_LEN: usize = 16 * 1024;
+/// Bytes usb_serial_isr() dropped because USB_QUEUE was full (wraps around), reported in the status reply
+static USB_DROPPED_BYTES: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+/// Bytes of outgoing frames (replies, acks, perf reports) waiting for room in the USB FIFO,
+/// enough for a few max length frames
+const TX_QUEUE_LEN: usize = 4 * MAX_FRAME_LEN;
+
+static FRAME_TIMER: Mutex<RefCell<Option<PeriodicTimer<'static, esp_hal::Blocking>>>> =
+  Mutex::new(RefCell::new(None));
+/// Set by frame_timer_isr() when the next frame is due, for Pacing::Timer
+static RENDER_DUE: AtomicBool = AtomicBool::new(false);
+
+/// The strips, ~45kB each, so they are kept out of main()'s stack
+static STRIPS: StaticCell<[LEDStrip; NUM_STRIPS]> = StaticCell::new();
+
+static mut USB_QUEUE: Queue<u8, USB_QUEUE_LEN> = Queue::new();
 static mut USB_PRODUCER: Option<Producer<'static, u8>> = None;
 
 #[handler]
@@ -49,13 +116,14 @@ fn usb_serial_isr() {
       // Read and store in buffer. Data will be processed in main loop.
       // I'd like to do "If buffer is full, discard oldest data."
       // But I made myself able to access only the Producer here (for performance gains hopefully)
-      // So instead just discard new data if full.
+      // So instead just discard new data if full, counting it in USB_DROPPED_BYTES.
       unsafe {
         #[allow(static_mut_refs)]
         if let Some(producer) = USB_PRODUCER.as_mut() {
+          let dropped = USB_DROPPED_BYTES.borrow(cs);
           while let Ok(byte) = usb_serial_rx.read_byte() {
             if producer.enqueue(byte).is_err() {
-              break; // Buffer full, discard remaining data
+              dropped.set(dropped.get().wrapping_add(1));
             }
           }
         }
@@ -65,11 +133,54 @@ fn usb_serial_isr() {
   });
 }
 
+#[handler]
+fn frame_timer_isr() {
+  critical_section::with(|cs| {
+    if let Some(timer) = FRAME_TIMER.borrow_ref_mut(cs).as_mut() {
+      timer.clear_interrupt();
+    }
+  });
+  RENDER_DUE.store(true, Ordering::Release);
+}
+
+/// Queue a whole frame for send_queued(), or drop it if it doesn't fit.
+/// Frames are never split, so the host never gets half a frame with another one after it.
+fn queue_reply(tx_queue: &mut Deque<u8, TX_QUEUE_LEN>, frame: &[u8]) {
+  if tx_queue.capacity() - tx_queue.len() < frame.len() {
+    return;
+  }
+  for &byte in frame {
+    tx_queue.push_back(byte).ok();
+  }
+}
+
+/// Write queued bytes with write_byte_nb() until the USB FIFO (64 bytes) is full, the rest waits for the
+/// next loop. Never blocks, so a host that stops reading or is unplugged only leaves the queue full.
+fn send_queued(usb_serial_tx: &mut UsbSerialJtagTx<'_, esp_hal::Blocking>, tx_queue: &mut Deque<u8, TX_QUEUE_LEN>) {
+  while let Some(&byte) = tx_queue.front() {
+    if usb_serial_tx.write_byte_nb(byte).is_err() {
+      break;
+    }
+    tx_queue.pop_front();
+  }
+  usb_serial_tx.flush_tx_nb().ok();
+}
+
+/// Wait a little for the frame timer. Only the ESP32-C3 actually sleeps here (WFI, until any interrupt fires).
+/// The ESP32-S3 returns right away and the caller spins: esp-hal has no wrapper for Xtensa's WAITI, and inline asm
+/// for Xtensa is still unstable, so the S3 gets the steadier timing of Pacing::Timer but no power saving.
+fn wait_for_interrupt() {
+  #[cfg(feature = "esp32c3")]
+  esp_hal::riscv::asm::wfi();
+  #[cfg(feature = "esp32s3")]
+  core::hint::spin_loop();
+}
+
 #[main]
 fn main() -> ! {
   let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
   let peripherals = esp_hal::init(config);
-  let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(80)).unwrap();
+  let rmt = Rmt::new(peripherals.RMT, Rate::from_hz(RMT_SOURCE_HZ)).unwrap();
 
   let consumer = unsafe {
     // These invariants have to be met to keep safety:
@@ -95,55 +206,217 @@ fn main() -> ! {
   }
   usb_serial_tx.flush_tx_nb().ok();
 
-  // Configure TX channel on GPIO3
-  let mut channel = rmt
-    .channel0
-    .configure_tx(peripherals.GPIO3,
-      TxChannelConfig::default()
-        .with_clk_divider(1)
-        .with_idle_output_level(Level::Low)
-        .with_idle_output(true)
-    )
-    .unwrap();
+  let strip_config = StripConfig::default();
+  // One TX channel per strip: strip 0 on GPIO3, strip 1 on GPIO4. Change the pins here,
+  // see docs/README.md for which ones work. Taken out while transmitting and put back by render()
+  let mut channels = [
+    Some(setup_strip(rmt.channel0, peripherals.GPIO3, strip_config).unwrap()),
+    Some(setup_strip(rmt.channel1, peripherals.GPIO4, strip_config).unwrap()),
+  ];
 
-  let mut strip: LEDStrip = LEDStrip::new();
+  // Each strip's config lives in its own sector of the ledconfig partition. Flashed without partitions.csv
+  // there is no such partition, and the configs are neither restored nor saved
+  let mut flash = FlashStorage::new(peripherals.FLASH);
+  let mut partition_table = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+  let config_partition = partitions::read_partition_table(&mut flash, &mut partition_table).ok().and_then(|table| {
+    (0..table.len())
+      .filter_map(|i| table.get_partition(i).ok())
+      .find(|partition| partition.label_as_str() == CONFIG_PARTITION_LABEL)
+  });
+  let mut config_flash = config_partition
+    .filter(|partition| partition.len() >= NUM_STRIPS as u32 * FlashStorage::SECTOR_SIZE)
+    .map(|partition| partition.as_embedded_storage(&mut flash));
+  let config_offsets: [u32; NUM_STRIPS] = core::array::from_fn(|strip| strip as u32 * FlashStorage::SECTOR_SIZE);
 
-  let mut pulse_buffer = [PulseCode::default(); NUM_LEDS * 24 + 1];
+  let strips = STRIPS.init_with(|| core::array::from_fn(|_| LEDStrip::new()));
+  let mut saved_configs = [[0u8; CONFIG_SNAPSHOT_LEN]; NUM_STRIPS];
+  for ((strip, saved_config), offset) in strips.iter_mut().zip(saved_configs.iter_mut()).zip(config_offsets) {
+    // Falls back to defaults if the flash is erased or corrupt
+    if config_flash.as_mut().is_some_and(|config_flash| config_flash.read(offset, saved_config).is_ok()) {
+      strip.config_restore(saved_config);
+    }
+    if SELF_TEST_ON_BOOT {
+      strip.start_self_test();
+    }
+  }
+  let mut last_config_save = Instant::now();
+  // Idle level each channel is configured with, updated when a strip's data line is inverted
+  let mut idle_levels = [strip_config.idle_level; NUM_STRIPS];
+  // Strip that commands go to, changed with the select strip action
+  let mut selected = 0;
+
+  // The whole frame, or just two chunks with packed-pulses
+  let mut pulse_buffer = [PulseCode::default(); RENDER_BUFFER_LEN];
   let delay = Delay::new();
-  let mut serial_parser = SerialParser::new(consumer);
+  if PACING == Pacing::Timer {
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let mut timer = PeriodicTimer::new(timg0.timer0);
+    timer.set_interrupt_handler(frame_timer_isr);
+    timer.listen();
+    critical_section::with(|cs| FRAME_TIMER.borrow_ref_mut(cs).replace(timer));
+  }
+  // Frame rate the timer runs at, 0 until it is started in the first loop
+  let mut timer_fps = 0;
+  // When each strip's next frame is due, so every strip runs at its own frame rate
+  let mut next_frames = [Instant::now(); NUM_STRIPS];
+  // TIMG1's watchdog, TIMG0 is left for the frame timer
+  let mut watchdog = TimerGroup::new(peripherals.TIMG1).wdt;
+  if WATCHDOG_TIMEOUT_MS != 0 {
+    watchdog.set_timeout(MwdtStage::Stage0, Duration::from_millis(WATCHDOG_TIMEOUT_MS));
+    watchdog.set_stage_action(MwdtStage::Stage0, MwdtStageAction::ResetSystem);
+    watchdog.enable();
+  }
+  // Partial frames are buffered here, must be at least MAX_FRAME_LEN
+  let mut serial_parser: SerialParser<PARSER_BUFFER_LEN> = SerialParser::new(consumer);
+  serial_parser.set_partial_frame_timeout(PARTIAL_FRAME_TIMEOUT_MS);
+  let mut reply_buffer = [0u8; MAX_FRAME_LEN];
+  // Outgoing bytes, written out a FIFO's worth at a time by send_queued()
+  let mut tx_queue: Deque<u8, TX_QUEUE_LEN> = Deque::new();
+  let mut last_command = Instant::now();
+  // Perf report accumulator, reset after every report
+  let mut perf_start = Instant::now();
+  let mut perf_frames: u32 = 0;
+  let mut perf_max_frame_us: u32 = 0;
 
   loop {
     let now = Instant::now();
+    watchdog.feed();
 
-    let frame_duration_ms = 1000.0 / (strip.get_frames_per_second() as f32);
+    // The loop runs at the fastest strip's frame rate, slower strips skip the loops in between
+    let loop_fps = strips.iter().map(LEDStrip::get_frames_per_second).max().unwrap_or(1);
+    let frame_duration_ms = 1000.0 / (loop_fps as f32);
 
+    serial_parser.set_time(now.duration_since_epoch().as_millis());
     let command = serial_parser.read_buffer_into_command();
     if let Some(command) = &command {
-      strip.apply_command(command);
+      last_command = Instant::now();
+      if command.action == SELECT_STRIP_ACTION {
+        // Out of range indices are ignored
+        if (command.data[0] as usize) < NUM_STRIPS {
+          selected = command.data[0] as usize;
+        }
+      }
+      let strip = &mut strips[selected];
+      // A command that can't be applied is skipped, there is nowhere to report it
+      let _ = strip.apply_command(command);
+
+      // Reply to commands which request data
+      let status = Status {
+        dropped_bytes: serial_parser
+          .get_dropped_bytes()
+          .wrapping_add(critical_section::with(|cs| USB_DROPPED_BYTES.borrow(cs).get())),
+        frame_count: strip.get_frame_count(),
+        uptime_ms: Instant::now().duration_since_epoch().as_millis(),
+        phase: strip.get_phase(),
+      };
+      let reply_len = strip.encode_response(command, &status, &mut reply_buffer);
+      if reply_len > 0 {
+        queue_reply(&mut tx_queue, &reply_buffer[..reply_len]);
+      }
+      // The pixel dump can take several frames, queued one chunk at a time through reply_buffer.
+      // Chunks that don't fit in the queue are dropped, the host sees the missing sequence numbers
+      if command.action == PIXEL_DUMP_ACTION {
+        let mut sequence = 0;
+        loop {
+          let chunk_len = strip.encode_pixel_dump(sequence, &mut reply_buffer);
+          if chunk_len == 0 {
+            break;
+          }
+          queue_reply(&mut tx_queue, &reply_buffer[..chunk_len]);
+          sequence += 1;
+        }
+      }
+      send_queued(&mut usb_serial_tx, &mut tx_queue);
     }
 
-    let changed = strip.update_pixels();
-    if changed {
-      strip.generate_pulse_data();
-      let pulse_data = strip.get_pulse_data(&mut pulse_buffer);
-      let transaction = channel.transmit(pulse_data).unwrap();
-      channel = transaction.wait().unwrap();
+    let strip_states = channels.iter_mut().zip(idle_levels.iter_mut()).zip(next_frames.iter_mut());
+    for (strip, ((channel, idle_level), next_frame)) in strips.iter_mut().zip(strip_states) {
+      // Fall back to a safe state if the host stopped sending (crashed, unplugged)
+      strip.check_host_timeout(last_command.elapsed().as_millis());
+
+      // Half a loop of slack, so a strip at the loop's own rate isn't skipped for coming round a bit early
+      if now + Duration::from_micros(500_000 / loop_fps as u64) < *next_frame {
+        continue;
+      }
+      // Frames that were missed are dropped rather than caught up on
+      *next_frame = (*next_frame + Duration::from_micros(1_000_000 / strip.get_frames_per_second() as u64)).max(now);
+
+      let Some(mut tx) = channel.take() else {
+        continue;
+      };
+      // Hold the line at the strip's idle level between frames, flipped when inverted
+      if strip.idle_level() != *idle_level
+        && tx.apply_config(&StripConfig { idle_level: strip.idle_level() }.tx_config()).is_ok()
+      {
+        *idle_level = strip.idle_level();
+      }
+      // Skip this frame on error rather than panicking, the strip keeps its last frame
+      // and render() sends it again next loop
+      *channel = Some(match strip.render(tx, &mut pulse_buffer) {
+        Ok(tx) => tx,
+        Err((_, tx)) => {
+          queue_reply(&mut tx_queue, b"RMT transmit error\n");
+          tx
+        }
+      });
     }
 
-    // Send response frame back to host PC as soon as frame is rendered
-    let response = [0xBB, 0x00, 0xDF, 0xF8];
-    for byte in response.iter() {
-      usb_serial_tx.write_byte_nb(*byte).ok();
+    // Only write when the config actually changed, to save flash erase cycles
+    if last_config_save.elapsed().as_millis() >= CONFIG_SAVE_INTERVAL_MS {
+      last_config_save = Instant::now();
+      for ((strip, saved_config), offset) in strips.iter().zip(saved_configs.iter_mut()).zip(config_offsets) {
+        let config = strip.config_snapshot();
+        let written = config != *saved_config
+          && config_flash.as_mut().is_some_and(|config_flash| config_flash.write(offset, &config).is_ok());
+        if written {
+          *saved_config = config;
+        }
+      }
     }
-    usb_serial_tx.flush_tx_nb().ok();
+
+    // Send response frame back to host PC as soon as frame is rendered
+    queue_reply(&mut tx_queue, &[0xBB, 0x00, 0xDF, 0xF8]);
+    send_queued(&mut usb_serial_tx, &mut tx_queue);
 
     // wait such that FRAME_DURATION_MS per frame is maintained
     let elapsed = now.elapsed();
 
+    // A longest frame time above 1000000 / fps means the loop can't keep up with the fps set
+    perf_frames += 1;
+    perf_max_frame_us = perf_max_frame_us.max(elapsed.as_micros() as u32);
+    if PERF_REPORT_FRAMES != 0 && perf_frames >= PERF_REPORT_FRAMES {
+      let fps = perf_frames as f32 * 1_000_000.0 / perf_start.elapsed().as_micros() as f32;
+      let report_len = encode_perf(fps, perf_max_frame_us, &mut reply_buffer);
+      queue_reply(&mut tx_queue, &reply_buffer[..report_len]);
+      send_queued(&mut usb_serial_tx, &mut tx_queue);
+      perf_start = Instant::now();
+      perf_frames = 0;
+      perf_max_frame_us = 0;
+    }
+
     // For some reason if this runs and I disconnect serial monitor, the strip stops updating.
     // Probably hanging on the write. Should use non-blocking (nb) write and flush
     // print_elapsed_time(&mut usb_serial_tx, elapsed);
 
-    delay.delay_micros(((frame_duration_ms * 1000.0) as u32).saturating_sub(elapsed.as_micros() as u32));
+    match PACING {
+      Pacing::BusyWait => {
+        delay.delay_micros(((frame_duration_ms * 1000.0) as u32).saturating_sub(elapsed.as_micros() as u32));
+      },
+      Pacing::Timer => {
+        // Restart the timer when the frame rate changes
+        let fps = loop_fps;
+        if fps != timer_fps {
+          critical_section::with(|cs| {
+            if let Some(timer) = FRAME_TIMER.borrow_ref_mut(cs).as_mut() {
+              timer.start(Duration::from_micros(1_000_000 / fps as u64)).ok();
+            }
+          });
+          timer_fps = fps;
+        }
+        while !RENDER_DUE.swap(false, Ordering::Acquire) {
+          wait_for_interrupt();
+        }
+      },
+    }
   }
 }
@@ -2,17 +2,81 @@ use core::panic;
 
 use heapless::spsc::Consumer;
 
+use crate::{MAX_GRADIENT_STOPS, MAX_SCROLL_PIXELS, NUM_LEDS, RGBPixel, VERSION};
+
+/// Maximum number of sub-commands in one compound command (action 0x05)
+pub const MAX_SUB_COMMANDS: usize = 16;
+/// Longest payload a frame can carry. A length of exactly MAX_PAYLOAD_LEN is valid.
+pub const MAX_PAYLOAD_LEN: usize = 1024;
+/// Maximum number of pixels in one pixel readback reply, so it fits in a MAX_PAYLOAD_LEN payload
+pub const MAX_READ_PIXELS: usize = (MAX_PAYLOAD_LEN - 2) / 3;
+/// Pixels in each chunk of a pixel dump, so a chunk fits in a MAX_PAYLOAD_LEN payload
+pub const PIXEL_DUMP_CHUNK_PIXELS: usize = (MAX_PAYLOAD_LEN - 4) / 3;
+/// Size of a frame with the longest payload: header + action + 2 bytes length + payload + 2 bytes checksum.
+/// SerialParser's buffer has to be at least this big.
+pub const MAX_FRAME_LEN: usize = 4 + MAX_PAYLOAD_LEN + 2;
+/// Default size of SerialParser's buffer, a max length frame with room to spare
+/// for the start of the next frame
+pub const PARSER_BUFFER_LEN: usize = MAX_FRAME_LEN + MAX_FRAME_LEN / 2;
+
+/// CRC-16-CCITT lookup table for SerialCommand::update_crc(), built at compile time.
+/// Entry `i` is update_crc_bitwise(0, i): what the top byte of the CRC XOR the next byte being `i` adds.
+const CRC16_TABLE: [u16; 256] = {
+  let mut table = [0u16; 256];
+  let mut i = 0;
+  while i < 256 {
+    // With a CRC of 0 the byte lands in the top 8 bits, just like (crc >> 8) ^ byte does
+    table[i] = SerialCommand::update_crc_bitwise(0, i as u8);
+    i += 1;
+  }
+  table
+};
+
+/// Checksum algorithm used by a frame, selected by its header (SOF) byte
+#[derive(Copy, Clone, PartialEq)]
+pub enum ChecksumKind {
+  /// CRC-16-CCITT, header 0xAA (default)
+  Crc16Ccitt,
+  /// XOR of all bytes in the low byte, high byte 0. Header 0xAB
+  Xor,
+  /// Fletcher-16, header 0xAC
+  Fletcher16,
+}
+
+impl ChecksumKind {
+  /// The checksum kind selected by a header byte, None if it isn't a header
+  pub fn from_header(header: u8) -> Option<Self> {
+    match header {
+      0xAA => Some(ChecksumKind::Crc16Ccitt),
+      0xAB => Some(ChecksumKind::Xor),
+      0xAC => Some(ChecksumKind::Fletcher16),
+      _ => None,
+    }
+  }
+}
+
 /// One frame (command) received over serial.
 /// It is guaranteed that data exists for the length specified.
 pub struct SerialCommand {
   /// Type of command
   pub action: u8,
-  /// Max 1024
+  /// Max MAX_PAYLOAD_LEN (1024)
   pub length: u16,
   /// Just a buffer, only `length` bytes are valid
-  pub data: [u8; 1024],
-  // CRC-16-CCITT checksum
+  pub data: [u8; MAX_PAYLOAD_LEN],
+  // Checksum, CRC-16-CCITT unless the header selected another kind
   pub checksum: u16,
+  /// Algorithm used for `checksum`
+  pub checksum_kind: ChecksumKind,
+}
+
+/// A command's action and payload without the rest of SerialCommand, borrowed from a SerialCommand
+/// (see SerialCommand::view()) or from a compound command's payload. `data` is exactly `length` bytes.
+#[derive(Copy, Clone)]
+pub struct CommandView<'a> {
+  pub action: u8,
+  pub length: u16,
+  pub data: &'a [u8],
 }
 
 impl SerialCommand {
@@ -20,43 +84,85 @@ impl SerialCommand {
     SerialCommand {
       action: 0,
       length: 0,
-      data: [0; 1024],
+      data: [0; MAX_PAYLOAD_LEN],
       checksum: 0,
+      checksum_kind: ChecksumKind::Crc16Ccitt,
     }
   }
 
-  /// Calculate CRC-16-CCITT checksum for the command
-  /// CRC is calculated over: action (1 byte) -> length (2 bytes) -> data (length bytes)
+  /// Calculate the checksum for the command using its checksum_kind
+  /// Checksum is calculated over: action (1 byte) -> length (2 bytes) -> data (length bytes)
   pub fn calculate_checksum(&self) -> u16 {
+    // The parser never builds a longer command, this only guards against a hand-built one
+    let data_len = (self.length as usize).min(MAX_PAYLOAD_LEN);
+    let payload = &self.data[..data_len];
+    match self.checksum_kind {
+      ChecksumKind::Crc16Ccitt => Self::checksum_of(self.action, payload),
+      ChecksumKind::Xor => Self::xor_checksum_of(self.action, payload),
+      ChecksumKind::Fletcher16 => Self::fletcher16_checksum_of(self.action, payload),
+    }
+  }
+
+  /// The bytes a checksum is calculated over: action, length (big-endian), payload
+  fn checksummed_bytes(action: u8, payload: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let length = (payload.len() as u16).to_be_bytes();
+    [action, length[0], length[1]].into_iter().chain(payload.iter().copied())
+  }
+
+  /// Calculate XOR checksum of a frame with the given action and payload
+  pub fn xor_checksum_of(action: u8, payload: &[u8]) -> u16 {
+    Self::checksummed_bytes(action, payload).fold(0u8, |acc, byte| acc ^ byte) as u16
+  }
+
+  /// Calculate Fletcher-16 checksum of a frame with the given action and payload
+  pub fn fletcher16_checksum_of(action: u8, payload: &[u8]) -> u16 {
+    let mut sum1: u16 = 0;
+    let mut sum2: u16 = 0;
+    for byte in Self::checksummed_bytes(action, payload) {
+      sum1 = (sum1 + byte as u16) % 255;
+      sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+  }
+
+  /// Calculate CRC-16-CCITT checksum of a frame with the given action and payload
+  pub fn checksum_of(action: u8, payload: &[u8]) -> u16 {
     let mut crc: u16 = 0xFFFF; // Initial value for CRC-16-CCITT
+    let length = payload.len() as u16;
 
     // Process action/ byte
-    crc = Self::update_crc(crc, self.action);
+    crc = Self::update_crc(crc, action);
 
     // Process length field (big-endian)
-    crc = Self::update_crc(crc, ((self.length >> 8) & 0xFF) as u8);
-    crc = Self::update_crc(crc, (self.length & 0xFF) as u8);
+    crc = Self::update_crc(crc, ((length >> 8) & 0xFF) as u8);
+    crc = Self::update_crc(crc, (length & 0xFF) as u8);
 
-    // Process data field (only up to length bytes)
-    let data_len = self.length.min(1024) as usize;
-    for i in 0..data_len {
-      crc = Self::update_crc(crc, self.data[i]);
+    // Process data field
+    for byte in payload.iter() {
+      crc = Self::update_crc(crc, *byte);
     }
 
     crc
   }
 
-  /// Update CRC-16-CCITT with one byte
+  /// Update CRC-16-CCITT with one byte, one table lookup instead of 8 shifts
   fn update_crc(crc: u16, byte: u8) -> u16 {
+    (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize]
+  }
+
+  /// Update CRC-16-CCITT with one byte, bit by bit. Slower than update_crc(), used to build CRC16_TABLE
+  const fn update_crc_bitwise(crc: u16, byte: u8) -> u16 {
     let mut crc = crc;
     crc ^= (byte as u16) << 8;
 
-    for _ in 0..8 {
+    let mut bit = 0;
+    while bit < 8 {
       if (crc & 0x8000) != 0 {
         crc = (crc << 1) ^ 0x1021; // CRC-16-CCITT polynomial
       } else {
         crc <<= 1;
       }
+      bit += 1;
     }
 
     crc
@@ -67,9 +173,33 @@ impl SerialCommand {
     self.checksum == self.calculate_checksum()
   }
 
+  /// The action and the `length` bytes of payload
+  pub fn view(&self) -> CommandView<'_> {
+    let length = (self.length as usize).min(MAX_PAYLOAD_LEN);
+    CommandView { action: self.action, length: length as u16, data: &self.data[..length] }
+  }
+
+  /// Validate that the action is valid and the length meets the minimum required
+  pub fn validate_length_with_action(&self) -> bool {
+    self.view().validate_length_with_action()
+  }
+
+  /// See CommandView::setting_brightness()
+  pub fn setting_brightness(&self) -> Option<f32> {
+    self.view().setting_brightness()
+  }
+
+  /// See CommandView::sub_command_at()
+  pub fn sub_command_at(&self, offset: usize) -> Option<(CommandView<'_>, usize)> {
+    self.view().sub_command_at(offset)
+  }
+}
+
+impl<'a> CommandView<'a> {
   /// Validate that the action is valid and the length meets the minimum required
   pub fn validate_length_with_action(&self) -> bool {
     match self.action {
+      0x00 => true, // Keepalive: payload ignored
       0x01 => self.length >= 1,  // Control on/off: 1 byte
       0x02 => {
         // Set value: at least 1 byte for value ID
@@ -78,67 +208,424 @@ impl SerialCommand {
         }
         // Check minimum length based on value ID
         match self.data[0] {
-          0x00 => self.length >= 5, // Global brightness: ID + 4 bytes f32
-          0x01 => self.length >= 5, // Phase step: ID + 4 bytes f32
+          0x00 => self.finite_f32_at(1), // Global brightness: ID + 4 bytes f32
+          0x01 => self.finite_f32_at(1), // Phase step: ID + 4 bytes f32
           0x02 => self.length >= 3, // Num LEDs to update: ID + 2 bytes u16
           0x03 => self.length >= 2, // Frames per second: ID + 1 byte u8
           0x04 => self.length >= 2, // Reverse animation: ID + 1 byte bool
+          0x05 => self.finite_f32_at(1), // Phase: ID + 4 bytes f32
+          0x06 => self.length >= 3, // Hue shift: ID + 2 bytes u16
+          0x07 => self.finite_f32_at(1), // Saturation: ID + 4 bytes f32
+          0x08 => self.finite_f32_at(1), // Value: ID + 4 bytes f32
+          0x09 => self.length >= 3, // Off mode: ID + 1 byte mode + 1 byte fade frames
+          0x0A => self.length >= 2, // Wrap manual input: ID + 1 byte bool
+          0x0B => self.length >= 4, // Idle color: ID + 3 bytes RGB
+          0x0C => self.length >= 3, // Overlay: ID + 1 byte overlay ID + 1 byte rate
+          0x0D => self.length >= 3, // Reset time: ID + 2 bytes u16
+          0x0E => self.finite_f32_at(1), // Animation speed: ID + 4 bytes f32
+          0x0F => self.finite_f32_at(1) && self.finite_f32_at(5), // Brightness profile: ID + 2 * 4 bytes f32
+          0x10 => self.finite_f32_at(1) && self.f32_at(1) > 0.0, // Gamma: ID + 4 bytes f32, must be positive
+          0x11 => self.length >= 3, // Update window start: ID + 2 bytes u16
+          0x12 => self.length >= 5, // Host timeout: ID + 4 bytes u32
+          0x13 => self.length >= 2, // Breathing curve: ID + 1 byte curve ID
+          0x14 => self.length >= 2, // Brightness clipping: ID + 1 byte bool
+          0x15 => self.length >= 2, // Inverted data line: ID + 1 byte bool
+          0x16 => self.length >= 2, // Channel floor: ID + 1 byte u8
+          0x17 => self.length >= 2, // Dither brightness: ID + 1 byte bool
+          0x18 => self.length >= 4, // Chase direction: ID + 1 byte bool + 2 bytes u16 start offset
+          0x19 => self.length >= 4, // Segment color format: ID + 2 bytes u16 first LED + 1 byte format
+          0x1A => self.length >= 2, // Render pipeline: ID + 1 byte stage bits
+          0x1B => self.length >= 2, // Chip timing: ID + 1 byte chip ID
+          0x1C => self.length >= 2, // Freeze: ID + 1 byte bool
+          0x1D => self.length >= 3, // Active length: ID + 2 bytes u16
+          0x1E => self.length >= 2, // Brightness curve: ID + 1 byte
+          0x1F => self.length >= 2, // Color vision mode: ID + 1 byte
+          0x20 => self.length >= 3, // Soft start: ID + 2 bytes u16
+          0x21 => self.length >= 4, // White balance: ID + 3 bytes RGB
+          0x22 => self.length >= 4, // Rainbow speed modulation: ID + 1 byte depth + 2 bytes u16 period
           _ => false, // Unknown value ID
         }
       }
-      0x03 => {
-        // Set StripSetting: at least 1 byte for setting ID
+      0x03 => self.validate_setting(), // Set StripSetting: setting ID + setting payload
+      0x04 => self.length >= 5,  // Manual color input: 2 bytes index + at least 3 bytes RGB
+      0x05 => self.validate_compound(), // Compound: sequence of valid sub-commands
+      0x06 => true, // Get version: no payload
+      0x07 => self.length >= 6,  // Manual HSV input: 2 bytes index + at least 4 bytes HSV
+      0x08 => true, // Get status: no payload
+      0x09 => self.validate_rle(), // RLE color input: 2 bytes index + runs of 4 bytes
+      0x0A => true, // Brightness up: optional 1 byte step
+      0x0B => true, // Brightness down: optional 1 byte step
+      0x0C => self.length == 0 || self.validate_setting(), // Host loss fallback: nothing or a setting payload
+      0x0D => self.validate_scroll_upload(), // Scroll buffer upload: 2 bytes length + 2 bytes index + RGB
+      0x0E => self.length >= 1, // Select strip: 1 byte strip index, handled in main.rs
+      0x0F => self.length >= 4, // Get pixels: 2 bytes index + 2 bytes count
+      0x10 => {
+        // Manual color input with format: at least 1 byte for format
         if self.length < 1 {
           return false;
         }
-        // Check minimum length based on setting ID
         match self.data[0] {
-          0x00 => self.length >= 1, // Custom: just ID
-          0x01 => self.length >= 4, // Breathing: ID + 3 bytes RGB
-          0x02 => self.length >= 4, // SolidColor: ID + 3 bytes RGB
-          0x03 => self.length >= 5, // RainbowCycle: ID + 4 bytes f32
-          _ => false, // Unknown setting ID
+          0x00 => self.length >= 6, // Per-pixel: format + 2 bytes index + at least 3 bytes RGB
+          0x01 => self.length >= 8, // Run fill: format + 2 bytes index + 2 bytes count + 3 bytes RGB
+          _ => false, // Unknown format
         }
       }
-      0x04 => self.length >= 5,  // Manual color input: 2 bytes index + at least 3 bytes RGB
+      0x11 => true, // Get strip info: no payload
+      0x12 => self.length >= 7, // Fill: 2 bytes index + 2 bytes count + 3 bytes RGB
+      0x13 => self.length >= 3, // Mask upload: 2 bytes index + at least 1 byte mask value
+      0x14 => true, // Refresh: no payload
+      0x15 => self.length >= 2, // Rotate: 2 bytes i16 offset
+      0x16 => true, // Pixel dump: no payload
       _ => false, // Unknown action
     }
   }
+
+  /// The f32 (big endian) at `index` in the payload
+  fn f32_at(&self, index: usize) -> f32 {
+    f32::from_be_bytes([self.data[index], self.data[index + 1], self.data[index + 2], self.data[index + 3]])
+  }
+
+  /// Whether the payload is long enough for an f32 at `index` and it is finite.
+  /// NaN or infinity would get stuck in the animation math (a NaN phase stays NaN), so they're rejected.
+  fn finite_f32_at(&self, index: usize) -> bool {
+    index + 4 <= self.length as usize && self.f32_at(index).is_finite()
+  }
+
+  /// Check a Set StripSetting payload: setting ID followed by that setting's payload
+  fn validate_setting(&self) -> bool {
+    // At least 1 byte for setting ID
+    if self.length < 1 {
+      return false;
+    }
+    // Check minimum length based on setting ID
+    match self.data[0] {
+      0x00 => self.length >= 1, // Off (all LEDs black, the strip stays on): just ID
+      0x01 => self.length >= 4, // Breathing: ID + 3 bytes RGB (+ optional 2 bytes min, max, see setting_brightness())
+      0x02 => self.length >= 4, // SolidColor: ID + 3 bytes RGB
+      0x03 => self.finite_f32_at(1), // RainbowCycle: ID + 4 bytes f32 (+ optional 2 bytes sat, val)
+      0x04 => self.finite_f32_at(1) && self.length >= 6, // RainbowChase: ID + 4 bytes f32 + 1 byte spacing
+      0x05 => self.length >= 5, // Ripple: ID + 3 bytes RGB + 1 byte origin
+      0x06 => self.length >= 8, // Marquee: ID + 2 * 3 bytes RGB + 1 byte block size
+      0x07 => self.length >= 7, // BreathingRGB: ID + 3 bytes RGB + 3 bytes phase offsets
+      0x08 => self.length >= 1, // SelfTest: just ID
+      0x09 => self.length >= 5, // FadeTo: ID + 3 bytes RGB + 1 byte rate
+      0x0A => self.finite_f32_at(1), // RainbowSweep: ID + 4 bytes f32
+      0x0B => self.length >= 3, // Color temperature: ID + 2 bytes u16 Kelvin
+      0x0C => self.finite_f32_at(1), // Scroll: ID + 4 bytes f32 speed
+      0x0D => self.length >= 1, // Custom (manual input): just ID
+      0x0E => self.finite_f32_at(1) && self.length >= 6, // RainbowComet: ID + 4 bytes f32 + 1 byte tail length
+      0x0F => self.length >= 5, // Wave: ID + 3 bytes RGB + 1 byte count
+      0x10 => self.length >= 7, // HueGradient: ID + 2 * 2 bytes u16 hue + 1 byte sat + 1 byte val
+      0x11 => self.length >= 6, // Life: ID + 3 bytes RGB + 1 byte rule + 1 byte frames per generation
+      0x12 => self.validate_gradient(), // Gradient: ID + 1 byte count + 4 bytes per stop
+      _ => false, // Unknown setting ID
+    }
+  }
+
+  /// Global brightness from the optional byte after a Set StripSetting payload (0-255 mapped to 0.0-1.0),
+  /// or None if the payload doesn't go that far. For settings with optional bytes, the brightness byte
+  /// comes either right after the required bytes or after all the optional ones,
+  /// e.g. Breathing is `01 R G B`, `01 R G B bright`, `01 R G B min max` or `01 R G B min max bright`.
+  pub fn setting_brightness(&self) -> Option<f32> {
+    // Payload length without and with the optional bytes
+    let (short_length, full_length) = match self.data[0] {
+      0x00 | 0x08 | 0x0D => (1, 1), // Off, SelfTest, Custom
+      0x0B => (3, 3), // Color temperature
+      0x01 => (4, 6), // Breathing (optional min, max)
+      0x02 => (4, 4), // SolidColor
+      0x03 => (5, 7), // RainbowCycle (optional sat, val)
+      0x05 | 0x09 | 0x0A | 0x0C | 0x0F => (5, 5), // Ripple, FadeTo, RainbowSweep, Scroll, Wave
+      0x04 | 0x0E | 0x11 => (6, 6), // RainbowChase, RainbowComet, Life
+      0x07 | 0x10 => (7, 7), // BreathingRGB, HueGradient
+      0x06 => (8, 8), // Marquee
+      0x12 => (2 + 4 * self.data[1] as usize, 2 + 4 * self.data[1] as usize), // Gradient, by number of stops
+      _ => return None,
+    };
+    let length = self.length as usize;
+    // Too short for the optional bytes, so the byte after the required ones is the brightness
+    let index = if length < full_length { short_length } else { full_length };
+    if length > index {
+      Some(self.data[index] as f32 / 255.0)
+    } else {
+      None
+    }
+  }
+
+  /// Check an RLE color input payload: 2 bytes start index followed by runs of
+  /// count (1 byte, non-zero) + RGB (3 bytes), which must not run past the end of the strip.
+  fn validate_rle(&self) -> bool {
+    let length = self.length as usize;
+    if length < 6 || !(length - 2).is_multiple_of(4) {
+      return false;
+    }
+    let start_index = u16::from_be_bytes([self.data[0], self.data[1]]) as usize;
+    let mut total = 0;
+    for run in self.data[2..length].chunks_exact(4) {
+      if run[0] == 0 {
+        return false;
+      }
+      total += run[0] as usize;
+    }
+    start_index + total <= NUM_LEDS
+  }
+
+  /// Check a Gradient setting payload: ID + count (1 to MAX_GRADIENT_STOPS) then position + RGB
+  /// for each stop, positions must not decrease
+  fn validate_gradient(&self) -> bool {
+    if self.length < 2 {
+      return false;
+    }
+    let count = self.data[1] as usize;
+    if count == 0 || count > MAX_GRADIENT_STOPS || (self.length as usize) < 2 + 4 * count {
+      return false;
+    }
+    let stops = &self.data[2..2 + 4 * count];
+    stops.chunks_exact(4).zip(stops.chunks_exact(4).skip(1)).all(|(a, b)| a[0] <= b[0])
+  }
+
+  /// Check a scroll buffer upload payload: 2 bytes framebuffer length, 2 bytes start index,
+  /// then whole RGB triplets, which must all fit in MAX_SCROLL_PIXELS.
+  fn validate_scroll_upload(&self) -> bool {
+    let length = self.length as usize;
+    if length < 4 || !(length - 4).is_multiple_of(3) {
+      return false;
+    }
+    let len = u16::from_be_bytes([self.data[0], self.data[1]]) as usize;
+    let start_index = u16::from_be_bytes([self.data[2], self.data[3]]) as usize;
+    len <= MAX_SCROLL_PIXELS && start_index + (length - 4) / 3 <= MAX_SCROLL_PIXELS
+  }
+
+  /// The sub-command starting at `offset` in a compound command's payload, borrowed from it.
+  /// Each sub-command is action (1 byte) -> length (2 bytes, big endian) -> data (length bytes).
+  /// Returns the sub-command and the offset of the next one,
+  /// or None if the sub-command does not fit in the remaining payload.
+  pub fn sub_command_at(&self, offset: usize) -> Option<(CommandView<'a>, usize)> {
+    let payload = self.data;
+    if offset + 3 > payload.len() {
+      return None;
+    }
+    let length = u16::from_be_bytes([payload[offset + 1], payload[offset + 2]]) as usize;
+    let end = offset + 3 + length;
+    if end > payload.len() {
+      return None;
+    }
+    let sub = CommandView { action: payload[offset], length: length as u16, data: &payload[offset + 3..end] };
+    Some((sub, end))
+  }
+
+  /// Check that a compound command's payload is made up entirely of valid sub-commands.
+  /// Compound commands cannot be nested, and at most MAX_SUB_COMMANDS are allowed.
+  fn validate_compound(&self) -> bool {
+    let mut offset = 0;
+    let mut count = 0;
+    while offset < self.length as usize {
+      count += 1;
+      if count > MAX_SUB_COMMANDS {
+        return false;
+      }
+      match self.sub_command_at(offset) {
+        Some((sub, next)) => {
+          if sub.action == 0x05 || !sub.validate_length_with_action() {
+            return false;
+          }
+          offset = next;
+        }
+        None => return false, // Sub-command length runs past the payload
+      }
+    }
+    count > 0
+  }
+}
+
+/// Encode a frame (0xAA + action + length + payload + CRC) into `buffer`.
+/// Used for replies to the host, so they are framed the same way as inbound frames.
+/// Returns the number of bytes written.
+pub fn encode_frame(action: u8, payload: &[u8], buffer: &mut [u8]) -> usize {
+  let length = payload.len();
+  let frame_size = 4 + length + 2;
+  if length > MAX_PAYLOAD_LEN || buffer.len() < frame_size {
+    panic!("Buffer too small for encoded frame");
+  }
+  buffer[0] = 0xAA;
+  buffer[1] = action;
+  buffer[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+  buffer[4..4 + length].copy_from_slice(payload);
+  let checksum = SerialCommand::checksum_of(action, payload);
+  buffer[4 + length..frame_size].copy_from_slice(&checksum.to_be_bytes());
+  frame_size
 }
 
+/// Runtime diagnostics, sent in reply to the status command
+pub struct Status {
+  /// Bytes dropped because the USB queue or SerialParser's buffer overflowed
+  pub dropped_bytes: u32,
+  /// Frames rendered by LEDStrip::update_pixels() since startup
+  pub frame_count: u64,
+  /// Milliseconds since startup
+  pub uptime_ms: u64,
+  /// Animation phase of the strip, 0.0 to 1.0, for hosts syncing their own animations to it
+  pub phase: f32,
+}
+
+/// Encode the status reply: dropped bytes (u32), frame count (u64), uptime in ms (u64), phase (f32),
+/// all big endian. Returns the number of bytes written.
+pub fn encode_status(status: &Status, buffer: &mut [u8]) -> usize {
+  let mut payload = [0u8; 24];
+  payload[0..4].copy_from_slice(&status.dropped_bytes.to_be_bytes());
+  payload[4..12].copy_from_slice(&status.frame_count.to_be_bytes());
+  payload[12..20].copy_from_slice(&status.uptime_ms.to_be_bytes());
+  payload[20..24].copy_from_slice(&status.phase.to_be_bytes());
+  encode_frame(0x08, &payload, buffer)
+}
+
+/// Action of the perf report, sent by the firmware on its own rather than in reply to a command
+pub const PERF_REPORT_ACTION: u8 = 0xF0;
+
+/// Encode the perf report: measured frames per second (f32) and longest frame time in microseconds (u32),
+/// both big endian. Returns the number of bytes written.
+pub fn encode_perf(fps: f32, max_frame_us: u32, buffer: &mut [u8]) -> usize {
+  let mut payload = [0u8; 8];
+  payload[0..4].copy_from_slice(&fps.to_be_bytes());
+  payload[4..8].copy_from_slice(&max_frame_us.to_be_bytes());
+  encode_frame(PERF_REPORT_ACTION, &payload, buffer)
+}
+
+/// Encode the version reply: VERSION (major, minor, patch) + NUM_LEDS (u16, big endian).
+/// Returns the number of bytes written.
+pub fn encode_version(buffer: &mut [u8]) -> usize {
+  let num_leds = (NUM_LEDS as u16).to_be_bytes();
+  let payload = [VERSION[0], VERSION[1], VERSION[2], num_leds[0], num_leds[1]];
+  encode_frame(0x06, &payload, buffer)
+}
+
+/// Encode the strip info reply: NUM_LEDS, update window start and num LEDs to update,
+/// all u16 big endian. Returns the number of bytes written.
+pub fn encode_strip_info(update_start: usize, num_leds_to_update: usize, buffer: &mut [u8]) -> usize {
+  let mut payload = [0u8; 6];
+  payload[0..2].copy_from_slice(&(NUM_LEDS as u16).to_be_bytes());
+  payload[2..4].copy_from_slice(&(update_start as u16).to_be_bytes());
+  payload[4..6].copy_from_slice(&(num_leds_to_update as u16).to_be_bytes());
+  encode_frame(0x11, &payload, buffer)
+}
+
+/// Encode the pixel readback reply: start index (u16, big endian) then RGB for each pixel.
+/// The range is clamped to the end of `pixels` and to MAX_READ_PIXELS.
+/// Returns the number of bytes written.
+pub fn encode_pixels(pixels: &[RGBPixel], start: usize, count: usize, buffer: &mut [u8]) -> usize {
+  let start = start.min(pixels.len());
+  let count = count.min(pixels.len() - start).min(MAX_READ_PIXELS);
+  let mut payload = [0u8; 2 + MAX_READ_PIXELS * 3];
+  payload[0..2].copy_from_slice(&(start as u16).to_be_bytes());
+  for (i, pixel) in pixels[start..start + count].iter().enumerate() {
+    payload[2 + i * 3..2 + i * 3 + 3].copy_from_slice(&[pixel.r, pixel.g, pixel.b]);
+  }
+  encode_frame(0x0F, &payload[..2 + count * 3], buffer)
+}
 
+/// Action of the pixel dump request and the chunks sent back
+pub const PIXEL_DUMP_ACTION: u8 = 0x16;
 
-pub struct SerialParser {
-  buffer: [u8; 1024 + 512], // extra space in case
+/// Encode chunk `sequence` of a pixel dump: sequence (u8), number of chunks (u8), start index (u16, big endian)
+/// then RGB for each pixel, PIXEL_DUMP_CHUNK_PIXELS pixels per chunk, the last one shorter.
+/// Returns the number of bytes written, 0 if `sequence` is past the last chunk.
+pub fn encode_pixels_chunk(pixels: &[RGBPixel], sequence: usize, buffer: &mut [u8]) -> usize {
+  let chunks = pixels.len().div_ceil(PIXEL_DUMP_CHUNK_PIXELS);
+  if sequence >= chunks {
+    return 0;
+  }
+  let start = sequence * PIXEL_DUMP_CHUNK_PIXELS;
+  let count = (pixels.len() - start).min(PIXEL_DUMP_CHUNK_PIXELS);
+  let mut payload = [0u8; 4 + PIXEL_DUMP_CHUNK_PIXELS * 3];
+  payload[0] = sequence as u8;
+  payload[1] = chunks as u8;
+  payload[2..4].copy_from_slice(&(start as u16).to_be_bytes());
+  for (i, pixel) in pixels[start..start + count].iter().enumerate() {
+    payload[4 + i * 3..4 + i * 3 + 3].copy_from_slice(&[pixel.r, pixel.g, pixel.b]);
+  }
+  encode_frame(PIXEL_DUMP_ACTION, &payload[..4 + count * 3], buffer)
+}
+
+/// Parses frames out of the bytes from the USB queue, buffering up to N bytes of partial frames.
+/// N must be at least MAX_FRAME_LEN, more leaves room for the next frame while one is waiting.
+pub struct SerialParser<const N: usize = PARSER_BUFFER_LEN> {
+  buffer: [u8; N],
   buffer_len_in_use: usize,
   consumer: Consumer<'static, u8>,
+  /// Number of bytes thrown away because the buffer overflowed
+  dropped_bytes: u32,
+  /// Milliseconds an incomplete frame may wait for the rest of its bytes, 0 to wait forever
+  partial_frame_timeout_ms: u64,
+  /// Current time in milliseconds, as last passed to set_time()
+  now_ms: u64,
+  /// now_ms when the last byte was added to the buffer
+  last_byte_ms: u64,
 }
 
-impl SerialParser {
-
+impl<const N: usize> SerialParser<N> {
   pub fn new(consumer: Consumer<'static, u8>) -> Self {
+    // Fails to compile if the buffer can't hold a max length frame
+    const { assert!(N >= MAX_FRAME_LEN, "SerialParser buffer must hold at least MAX_FRAME_LEN bytes") };
     SerialParser {
-      buffer: [0; 1024 + 512],
+      buffer: [0; N],
       buffer_len_in_use: 0,
       consumer,
+      dropped_bytes: 0,
+      partial_frame_timeout_ms: 0,
+      now_ms: 0,
+      last_byte_ms: 0,
     }
   }
 
-  /// Add a byte to the buffer
+  /// Drop an incomplete frame once no bytes arrived for `timeout_ms`, e.g. when the host crashed mid-frame,
+  /// so it doesn't hold up the frames behind it. 0 (default) waits forever. Needs set_time() to be called.
+  pub fn set_partial_frame_timeout(&mut self, timeout_ms: u64) {
+    self.partial_frame_timeout_ms = timeout_ms;
+  }
+
+  /// Tell the parser the current time in milliseconds, used for the partial frame timeout.
+  /// Call it before read_buffer_into_command(), the parser has no clock of its own.
+  pub fn set_time(&mut self, now_ms: u64) {
+    self.now_ms = now_ms;
+  }
+
+  /// Whether the bytes in the buffer have waited longer than the partial frame timeout
+  fn is_stale(&self) -> bool {
+    self.partial_frame_timeout_ms != 0
+      && self.now_ms.saturating_sub(self.last_byte_ms) >= self.partial_frame_timeout_ms
+  }
+
+  /// Number of bytes dropped due to buffer overflow since startup (wraps around)
+  pub fn get_dropped_bytes(&self) -> u32 {
+    self.dropped_bytes
+  }
+
+  /// Add a byte to the buffer.
+  /// If the buffer is full, everything in it is dropped and counted in dropped_bytes.
   fn buffer_push(&mut self, byte: u8) {
     if self.buffer_len_in_use >= self.buffer.len() {
-      panic!("Buffer overflow in SerialParser");
+      self.dropped_bytes = self.dropped_bytes.wrapping_add(self.buffer_len_in_use as u32);
+      self.buffer_len_in_use = 0;
     }
     self.buffer[self.buffer_len_in_use] = byte;
     self.buffer_len_in_use += 1;
+    self.last_byte_ms = self.now_ms;
+  }
+
+  /// Feed bytes straight into the parse buffer, bypassing the consumer.
+  /// Useful for feeding a frame in several chunks, as if split across USB packets.
+  /// Overflowing the buffer drops its contents, the same as bytes from the consumer.
+  pub fn feed_bytes(&mut self, bytes: &[u8]) {
+    for byte in bytes.iter() {
+      self.buffer_push(*byte);
+    }
   }
 
-  /// Find the next 0xAA header byte in the buffer and shift data to the beginning.
+  /// Find the next header byte (0xAA, or another ChecksumKind header) in the buffer and shift data to the beginning.
   /// Returns true if a header was found, false if no header exists in the buffer.
   fn find_next_header_and_shift(&mut self) -> bool {
-    // Look for the next 0xAA starting from index 1 (skip the first byte)
+    // Look for the next header starting from index 1 (skip the first byte)
     for i in 1..self.buffer_len_in_use {
-      if self.buffer[i] == 0xAA {
+      if ChecksumKind::from_header(self.buffer[i]).is_some() {
         // Found a header, shift data to the beginning
         let shift_amount = i;
         let new_len = self.buffer_len_in_use - shift_amount;
@@ -156,6 +643,34 @@ impl SerialParser {
     false
   }
 
+  /// Move bytes from the consumer into the parse buffer, stopping once it is full
+  /// (it may already be full from feed_bytes). read_buffer_into_command() does this itself.
+  pub fn fill_buffer(&mut self) {
+    while self.buffer_len_in_use < self.buffer.len() {
+      match self.consumer.dequeue() {
+        Some(byte) => self.buffer_push(byte),
+        None => break,
+      }
+    }
+  }
+
+  /// Check whether the buffer holds at least one whole frame: a header followed by a valid length
+  /// and that many bytes plus the checksum. Doesn't check the action or checksum, so
+  /// read_buffer_into_command() can still reject it. Call fill_buffer() first to include queued bytes.
+  pub fn has_complete_frame(&self) -> bool {
+    let buffer = &self.buffer[..self.buffer_len_in_use];
+    for start in 0..buffer.len() {
+      if ChecksumKind::from_header(buffer[start]).is_none() || start + 4 > buffer.len() {
+        continue;
+      }
+      let length = u16::from_be_bytes([buffer[start + 2], buffer[start + 3]]) as usize;
+      if length <= MAX_PAYLOAD_LEN && start + 4 + length + 2 <= buffer.len() {
+        return true;
+      }
+    }
+    false
+  }
+
   // 1. Fill buffer from consumer until we have enough data or consumer is empty
   // 2. Try to parse a frame from the buffer
   // 3. If frame is malformed, find next header in buffer and retry
@@ -166,38 +681,35 @@ impl SerialParser {
   ) -> Option<SerialCommand> {
 
     loop {
-      // Fill buffer from consumer
-      while let Some(byte) = self.consumer.dequeue() {
-        self.buffer_push(byte);
-
-        if self.buffer_len_in_use >= self.buffer.len() {
-          break;
-        }
-      }
+      self.fill_buffer();
 
       if self.buffer_len_in_use == 0 {
         return None;
       }
 
       // Ensure the first byte is a header
-      if self.buffer[0] != 0xAA {
+      let Some(checksum_kind) = ChecksumKind::from_header(self.buffer[0]) else {
         // Find the next header and shift
         if !self.find_next_header_and_shift() {
           return None;
         } else {
           continue;
         }
-      }
+      };
 
       // Check if we have at least enough bytes for header + action + length
       if self.buffer_len_in_use < 4 {
+        // The rest is never coming, resync from the next header
+        if self.is_stale() && self.find_next_header_and_shift() {
+          continue;
+        }
         return None;
       }
 
       let action = self.buffer[1];
       let length = ((self.buffer[2] as u16) << 8) | (self.buffer[3] as u16);
 
-      if length > 1024 {
+      if length as usize > MAX_PAYLOAD_LEN {
         // Invalid length, find next header
         if !self.find_next_header_and_shift() {
           return None;
@@ -207,14 +719,19 @@ impl SerialParser {
       }
 
       // Check if we have enough bytes for the complete frame
+      // A length of 0 is valid (e.g. queries), making the smallest frame 6 bytes
       let frame_size = 4 + (length as usize) + 2; // header + action + length_bytes + payload + checksum
       if self.buffer_len_in_use < frame_size {
+        if self.is_stale() && self.find_next_header_and_shift() {
+          continue;
+        }
         return None;
       }
 
       let mut result = SerialCommand::new();
       result.action = action;
       result.length = length;
+      result.checksum_kind = checksum_kind;
       for i in 0..length as usize {
         result.data[i] = self.buffer[4 + i];
       }
@@ -250,3 +767,412 @@ impl SerialParser {
   }
 
 }
+
+#[cfg(test)]
+impl SerialCommand {
+  /// A command with `payload` and a matching checksum, as the parser would hand it over
+  pub(crate) fn from_payload(action: u8, payload: &[u8]) -> Self {
+    let mut command = SerialCommand::new();
+    command.action = action;
+    command.length = payload.len() as u16;
+    command.data[..payload.len()].copy_from_slice(payload);
+    command.checksum = command.calculate_checksum();
+    command
+  }
+}
+
+/// The shortest valid Set StripSetting payload of every setting ID, in order
+#[cfg(test)]
+pub(crate) const SHORTEST_SETTINGS: [&[u8]; 19] = [
+  &[0x00],
+  &[0x01, 10, 20, 30],
+  &[0x02, 10, 20, 30],
+  &[0x03, 0x3F, 0x80, 0x00, 0x00],
+  &[0x04, 0x3F, 0x80, 0x00, 0x00, 3],
+  &[0x05, 10, 20, 30, 128],
+  &[0x06, 10, 20, 30, 40, 50, 60, 4],
+  &[0x07, 10, 20, 30, 0, 85, 170],
+  &[0x08],
+  &[0x09, 10, 20, 30, 8],
+  &[0x0A, 0x3F, 0x80, 0x00, 0x00],
+  &[0x0B, 0x0F, 0xA0],
+  &[0x0C, 0x3F, 0x80, 0x00, 0x00],
+  &[0x0D],
+  &[0x0E, 0x3F, 0x80, 0x00, 0x00, 5],
+  &[0x0F, 10, 20, 30, 2],
+  &[0x10, 0x00, 0x00, 0x00, 0x78, 255, 255],
+  &[0x11, 10, 20, 30, 90, 2],
+  &[0x12, 1, 0, 10, 20, 30],
+];
+
+#[cfg(test)]
+mod tests {
+  extern crate std;
+  use super::*;
+  use heapless::spsc::Queue;
+  use std::boxed::Box;
+
+  #[test]
+  fn crc16_table_matches_bitwise() {
+    for crc in [0x0000, 0xFFFF, 0x1D0F, 0x8000] {
+      for byte in 0..=255u8 {
+        assert_eq!(SerialCommand::update_crc(crc, byte), SerialCommand::update_crc_bitwise(crc, byte));
+      }
+    }
+  }
+
+  #[test]
+  fn crc16_check_value() {
+    // CRC-16/CCITT-FALSE check value
+    let crc = b"123456789".iter().fold(0xFFFF, |crc, &byte| SerialCommand::update_crc(crc, byte));
+    assert_eq!(crc, 0x29B1);
+  }
+
+  #[test]
+  fn calculate_checksum_covers_action_length_and_payload() {
+    let mut command = SerialCommand::new();
+    command.action = 0x01;
+    command.length = 1;
+    command.data[0] = 0x01;
+    assert_eq!(command.calculate_checksum(), 0xD164);
+    assert_eq!(command.calculate_checksum(), SerialCommand::checksum_of(0x01, &[0x01]));
+
+    command.action = 0x06;
+    command.length = 0;
+    assert_eq!(command.calculate_checksum(), 0x7E3C);
+  }
+
+  /// Payload of an encoded reply frame, after checking its header, length and CRC
+  fn reply_payload(action: u8, frame: &[u8]) -> &[u8] {
+    assert_eq!(frame[0], 0xAA);
+    assert_eq!(frame[1], action);
+    let length = u16::from_be_bytes([frame[2], frame[3]]) as usize;
+    assert_eq!(frame.len(), 4 + length + 2);
+    let payload = &frame[4..4 + length];
+    let crc = u16::from_be_bytes([frame[4 + length], frame[5 + length]]);
+    assert_eq!(crc, SerialCommand::checksum_of(action, payload));
+    payload
+  }
+
+  fn numbered_pixels<const N: usize>() -> [RGBPixel; N] {
+    core::array::from_fn(|i| RGBPixel::new(i as u8, (i >> 8) as u8, 0xAB))
+  }
+
+  #[test]
+  fn encode_pixels_reply() {
+    let pixels: [RGBPixel; NUM_LEDS] = numbered_pixels();
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    let len = encode_pixels(&pixels, 5, 2, &mut buffer);
+    let payload = reply_payload(0x0F, &buffer[..len]);
+    assert_eq!(payload, [0, 5, 5, 0, 0xAB, 6, 0, 0xAB]);
+  }
+
+  #[test]
+  fn encode_pixels_clamps_count_to_max_read_pixels() {
+    let pixels: [RGBPixel; 2 * MAX_READ_PIXELS] = numbered_pixels();
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    let len = encode_pixels(&pixels, 0, 0xFFFF, &mut buffer);
+    let payload = reply_payload(0x0F, &buffer[..len]);
+    assert_eq!(payload.len(), 2 + MAX_READ_PIXELS * 3);
+    assert!(payload.len() <= MAX_PAYLOAD_LEN);
+    let last = &payload[payload.len() - 3..];
+    assert_eq!(last, [(MAX_READ_PIXELS - 1) as u8, ((MAX_READ_PIXELS - 1) >> 8) as u8, 0xAB]);
+  }
+
+  #[test]
+  fn encode_pixels_clamps_range_to_strip() {
+    let pixels: [RGBPixel; NUM_LEDS] = numbered_pixels();
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    // Runs past the end: only the last 2 pixels
+    let len = encode_pixels(&pixels, NUM_LEDS - 2, 10, &mut buffer);
+    let payload = reply_payload(0x0F, &buffer[..len]);
+    assert_eq!(payload.len(), 2 + 2 * 3);
+    assert_eq!(u16::from_be_bytes([payload[0], payload[1]]) as usize, NUM_LEDS - 2);
+    // Starts past the end: no pixels, start clamped to the end
+    let len = encode_pixels(&pixels, NUM_LEDS + 100, 10, &mut buffer);
+    let payload = reply_payload(0x0F, &buffer[..len]);
+    assert_eq!(payload, (NUM_LEDS as u16).to_be_bytes());
+  }
+
+  /// `payload` with `extra` added on the end
+  fn extended(payload: &[u8], extra: &[u8]) -> SerialCommand {
+    let mut bytes = [0u8; 32];
+    bytes[..payload.len()].copy_from_slice(payload);
+    bytes[payload.len()..payload.len() + extra.len()].copy_from_slice(extra);
+    SerialCommand::from_payload(0x03, &bytes[..payload.len() + extra.len()])
+  }
+
+  #[test]
+  fn setting_brightness_for_every_setting() {
+    for (id, payload) in SHORTEST_SETTINGS.iter().enumerate() {
+      assert_eq!(payload[0] as usize, id);
+      let without = extended(payload, &[]);
+      assert!(without.validate_length_with_action(), "setting {id:#04x}");
+      assert_eq!(without.setting_brightness(), None, "setting {id:#04x}");
+      let with = extended(payload, &[0x33]);
+      assert!(with.validate_length_with_action(), "setting {id:#04x}");
+      assert_eq!(with.setting_brightness(), Some(0x33 as f32 / 255.0), "setting {id:#04x}");
+    }
+    // Shorter than the shortest is rejected
+    for payload in SHORTEST_SETTINGS.iter().filter(|payload| payload.len() > 1) {
+      assert!(!extended(&payload[..payload.len() - 1], &[]).validate_length_with_action());
+    }
+  }
+
+  #[test]
+  fn setting_brightness_after_optional_bytes() {
+    // RainbowCycle with saturation and value
+    let cycle = SHORTEST_SETTINGS[0x03];
+    assert_eq!(extended(cycle, &[200, 100]).setting_brightness(), None);
+    assert_eq!(extended(cycle, &[200, 100, 0x33]).setting_brightness(), Some(0x33 as f32 / 255.0));
+    // Gradient's length depends on the number of stops
+    let gradient = [0x12, 2, 0, 10, 20, 30, 255, 40, 50, 60];
+    assert_eq!(extended(&gradient, &[]).setting_brightness(), None);
+    assert_eq!(extended(&gradient, &[0x33]).setting_brightness(), Some(0x33 as f32 / 255.0));
+  }
+
+  #[test]
+  fn every_valid_setting_has_a_brightness_position() {
+    // Catches a setting added to validate_setting() but not to setting_brightness()
+    for id in 0..=255u8 {
+      let command = extended(&[id, 0x3F, 0x80, 0x00, 0x00, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], &[]);
+      if command.validate_length_with_action() {
+        assert!(command.setting_brightness().is_some(), "setting {id:#04x}");
+      }
+    }
+    assert!(!extended(&[SHORTEST_SETTINGS.len() as u8], &[]).validate_length_with_action());
+  }
+
+  #[test]
+  fn breathing_lengths() {
+    let breathing = |payload: &[u8]| SerialCommand::from_payload(0x03, payload);
+    assert!(!breathing(&[0x01, 10, 20]).validate_length_with_action());
+    // RGB, RGB + brightness, RGB + min + max, RGB + min + max + brightness
+    let rgb = breathing(&[0x01, 10, 20, 30]);
+    let rgb_brightness = breathing(&[0x01, 10, 20, 30, 51]);
+    let min_max = breathing(&[0x01, 10, 20, 30, 40, 200]);
+    let min_max_brightness = breathing(&[0x01, 10, 20, 30, 40, 200, 102]);
+    for command in [&rgb, &rgb_brightness, &min_max, &min_max_brightness] {
+      assert!(command.validate_length_with_action());
+    }
+    assert_eq!(rgb.setting_brightness(), None);
+    assert_eq!(rgb_brightness.setting_brightness(), Some(51.0 / 255.0));
+    assert_eq!(min_max.setting_brightness(), None);
+    assert_eq!(min_max_brightness.setting_brightness(), Some(102.0 / 255.0));
+  }
+
+  #[test]
+  fn rle_runs_must_be_whole_non_zero_and_fit() {
+    let rle = |payload: &[u8]| SerialCommand::from_payload(0x09, payload).validate_length_with_action();
+    assert!(rle(&[0x00, 0x00, 3, 10, 20, 30, 2, 40, 50, 60]));
+    // No runs, a partial run, or a run of 0 LEDs
+    assert!(!rle(&[0x00, 0x00]));
+    assert!(!rle(&[0x00, 0x00, 3, 10, 20, 30, 2, 40]));
+    assert!(!rle(&[0x00, 0x00, 3, 10, 20, 30, 0, 40, 50, 60]));
+    // Runs ending exactly at the last LED fit, one more doesn't
+    let last = ((NUM_LEDS - 5) as u16).to_be_bytes();
+    assert!(rle(&[last[0], last[1], 2, 1, 2, 3, 3, 4, 5, 6]));
+    assert!(!rle(&[last[0], last[1], 3, 1, 2, 3, 3, 4, 5, 6]));
+  }
+
+  #[test]
+  fn calculate_checksum_ignores_bytes_past_length() {
+    let mut command = SerialCommand::new();
+    command.action = 0x01;
+    command.length = 1;
+    command.data[0] = 0x01;
+    let checksum = command.calculate_checksum();
+    command.data[1] = 0xFF;
+    assert_eq!(command.calculate_checksum(), checksum);
+  }
+
+  /// A parser reading from a queue of its own, with `bytes` already queued
+  fn parser_with(bytes: &[u8]) -> SerialParser {
+    let (mut producer, consumer) = Box::leak(Box::new(Queue::<u8, 4096>::new())).split();
+    for &byte in bytes {
+      producer.enqueue(byte).unwrap();
+    }
+    SerialParser::new(consumer)
+  }
+
+  /// encode_frame() with `header` and the checksum it selects instead of 0xAA and CRC-16-CCITT
+  fn encode_frame_with(header: u8, action: u8, payload: &[u8], buffer: &mut [u8]) -> usize {
+    let len = encode_frame(action, payload, buffer);
+    let checksum = match ChecksumKind::from_header(header).unwrap() {
+      ChecksumKind::Crc16Ccitt => SerialCommand::checksum_of(action, payload),
+      ChecksumKind::Xor => SerialCommand::xor_checksum_of(action, payload),
+      ChecksumKind::Fletcher16 => SerialCommand::fletcher16_checksum_of(action, payload),
+    };
+    buffer[0] = header;
+    buffer[len - 2..len].copy_from_slice(&checksum.to_be_bytes());
+    len
+  }
+
+  #[test]
+  fn xor_and_fletcher16_check_values() {
+    // Over 01 00 01 01: action, length, payload
+    assert_eq!(SerialCommand::xor_checksum_of(0x01, &[0x01]), 0x0001);
+    assert_eq!(SerialCommand::fletcher16_checksum_of(0x01, &[0x01]), 0x0703);
+  }
+
+  #[test]
+  fn parser_accepts_xor_and_fletcher16_frames() {
+    for (header, kind) in [(0xAB, ChecksumKind::Xor), (0xAC, ChecksumKind::Fletcher16)] {
+      let mut frame = [0u8; 32];
+      let len = encode_frame_with(header, 0x04, &[0x00, 0x02, 10, 20, 30], &mut frame);
+      let command = parser_with(&frame[..len]).read_buffer_into_command().unwrap();
+      assert!(command.checksum_kind == kind);
+      assert_eq!((command.action, command.length), (0x04, 5));
+      assert_eq!(command.data[..5], [0x00, 0x02, 10, 20, 30]);
+      assert_eq!(command.checksum, command.calculate_checksum());
+    }
+  }
+
+  #[test]
+  fn parser_rejects_bad_xor_and_fletcher16_checksums() {
+    for header in [0xAB, 0xAC] {
+      let mut frame = [0u8; 32];
+      let len = encode_frame_with(header, 0x04, &[0x00, 0x02, 10, 20, 30], &mut frame);
+      // A payload byte changed in transit
+      frame[6] ^= 0x01;
+      assert!(parser_with(&frame[..len]).read_buffer_into_command().is_none());
+      // The CRC of the frame isn't accepted for these kinds either
+      let len = encode_frame(0x04, &[0x00, 0x02, 10, 20, 30], &mut frame);
+      frame[0] = header;
+      assert!(parser_with(&frame[..len]).read_buffer_into_command().is_none());
+    }
+  }
+
+  #[test]
+  fn parser_resyncs_past_a_bogus_xor_header() {
+    // A stray 0xAB in the junk before the real frames looks like the start of a 1 byte XOR frame
+    let mut bytes = [0u8; 32];
+    bytes[..5].copy_from_slice(&[0x12, 0xAB, 0x01, 0x00, 0x01]);
+    let mut len = 5;
+    len += encode_frame_with(0xAB, 0x01, &[0x01], &mut bytes[len..]);
+    len += encode_frame_with(0xAC, 0x01, &[0x00], &mut bytes[len..]);
+    let mut parser = parser_with(&bytes[..len]);
+    let command = parser.read_buffer_into_command().unwrap();
+    assert!(command.checksum_kind == ChecksumKind::Xor);
+    assert_eq!((command.action, command.data[0]), (0x01, 0x01));
+    let command = parser.read_buffer_into_command().unwrap();
+    assert!(command.checksum_kind == ChecksumKind::Fletcher16);
+    assert_eq!((command.action, command.data[0]), (0x01, 0x00));
+    assert!(parser.read_buffer_into_command().is_none());
+  }
+
+  #[test]
+  fn parser_accepts_max_payload_len() {
+    let payload: [u8; MAX_PAYLOAD_LEN] = core::array::from_fn(|i| i as u8);
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let len = encode_frame(0x00, &payload, &mut frame);
+    assert_eq!(len, MAX_FRAME_LEN);
+    let mut parser = parser_with(&frame);
+    let command = parser.read_buffer_into_command().unwrap();
+    assert_eq!(command.length as usize, MAX_PAYLOAD_LEN);
+    assert_eq!(command.data, payload);
+    assert_eq!(command.checksum, command.calculate_checksum());
+    assert!(parser.read_buffer_into_command().is_none());
+  }
+
+  #[test]
+  fn parser_overflow_counts_dropped_bytes_and_recovers() {
+    let mut parser = parser_with(&[]);
+    // Half a frame that never gets finished, then junk until the buffer is full
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    encode_frame(0x00, &[0x11; 100], &mut frame);
+    parser.feed_bytes(&frame[..50]);
+    parser.feed_bytes(&[0x00; PARSER_BUFFER_LEN - 50]);
+    assert_eq!(parser.get_dropped_bytes(), 0);
+    // The next byte overflows it, everything buffered so far is dropped
+    let len = encode_frame(0x01, &[0x01], &mut frame);
+    parser.feed_bytes(&frame[..len]);
+    assert_eq!(parser.get_dropped_bytes(), PARSER_BUFFER_LEN as u32);
+    let command = parser.read_buffer_into_command().unwrap();
+    assert_eq!((command.action, command.length, command.data[0]), (0x01, 1, 0x01));
+    assert!(parser.read_buffer_into_command().is_none());
+  }
+
+  #[test]
+  fn parser_rejects_payload_past_max_len_and_resyncs() {
+    // Zeros for the payload and checksum, so the only header after it is the next frame's
+    let mut bytes = [0u8; MAX_FRAME_LEN + 1 + 7];
+    bytes[..4].copy_from_slice(&[0xAA, 0x00, 0x04, 0x01]);
+    encode_frame(0x01, &[0x01], &mut bytes[MAX_FRAME_LEN + 1..]);
+    let mut parser = parser_with(&bytes);
+    let command = parser.read_buffer_into_command().unwrap();
+    assert_eq!((command.action, command.length, command.data[0]), (0x01, 1, 0x01));
+    assert!(parser.read_buffer_into_command().is_none());
+  }
+
+  #[test]
+  fn parser_joins_a_frame_fed_in_chunks() {
+    let payload: [u8; MAX_PAYLOAD_LEN] = core::array::from_fn(|i| (i * 7) as u8);
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let len = encode_frame(0x01, &payload, &mut frame);
+    let mut parser = parser_with(&[]);
+    // Uneven chunks, like USB reads, with the header and checksum split off on their own
+    let mut commands = 0;
+    for chunk in [&frame[..1], &frame[1..64], &frame[64..500], &frame[500..len - 1], &frame[len - 1..len]] {
+      parser.feed_bytes(chunk);
+      while let Some(command) = parser.read_buffer_into_command() {
+        commands += 1;
+        assert_eq!(command.action, 0x01);
+        assert_eq!(command.length as usize, MAX_PAYLOAD_LEN);
+        assert_eq!(command.data, payload);
+      }
+    }
+    assert_eq!(commands, 1);
+    assert_eq!(parser.get_dropped_bytes(), 0);
+  }
+
+  #[test]
+  fn has_complete_frame_for_empty_partial_and_whole_buffers() {
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let len = encode_frame(0x01, &[0x01], &mut frame);
+    let mut parser = parser_with(&[]);
+    assert!(!parser.has_complete_frame());
+    // Junk then everything but the checksum's last byte
+    parser.feed_bytes(&[0x12, 0x34]);
+    parser.feed_bytes(&frame[..len - 1]);
+    assert!(!parser.has_complete_frame());
+    parser.feed_bytes(&frame[len - 1..len]);
+    assert!(parser.has_complete_frame());
+    assert!(parser.read_buffer_into_command().is_some());
+    assert!(!parser.has_complete_frame());
+
+    // Queued bytes only count once they're pulled into the buffer
+    let mut parser = parser_with(&frame[..len]);
+    assert!(!parser.has_complete_frame());
+    parser.fill_buffer();
+    assert!(parser.has_complete_frame());
+  }
+
+  #[test]
+  fn stale_partial_frame_is_dropped_after_the_timeout() {
+    let mut parser = parser_with(&[]);
+    parser.set_partial_frame_timeout(100);
+    parser.set_time(1_000);
+    // A frame claiming 100 bytes that stops after 2, then a whole frame
+    parser.feed_bytes(&[0xAA, 0x01, 0x00, 100, 0x11, 0x11]);
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let len = encode_frame(0x01, &[0x01], &mut frame);
+    parser.feed_bytes(&frame[..len]);
+    // The valid frame is stuck behind the truncated one until the timeout
+    assert!(parser.read_buffer_into_command().is_none());
+    parser.set_time(1_099);
+    assert!(parser.read_buffer_into_command().is_none());
+    parser.set_time(1_100);
+    let command = parser.read_buffer_into_command().unwrap();
+    assert_eq!(command.action, 0x01);
+    assert_eq!(command.data[..1], [0x01]);
+    assert!(parser.read_buffer_into_command().is_none());
+
+    // Without a timeout it waits forever
+    let mut parser = parser_with(&[]);
+    parser.feed_bytes(&[0xAA, 0x01, 0x00, 100, 0x11, 0x11]);
+    parser.feed_bytes(&frame[..len]);
+    parser.set_time(u64::MAX);
+    assert!(parser.read_buffer_into_command().is_none());
+  }
+}
@@ -0,0 +1,255 @@
+use esp_hal::Blocking;
+use esp_hal::gpio::Level;
+use esp_hal::gpio::interconnect::PeripheralOutput;
+use esp_hal::rmt::{Channel, ConfigError, Error as RmtError, PulseCode, Tx, TxChannelConfig, TxChannelCreator};
+#[cfg(feature = "spi-dma")]
+use esp_hal::{
+  dma::{DmaChannelFor, DmaTxBuf},
+  spi::master::{AnySpi, Config as SpiConfig, ConfigError as SpiConfigError, Instance as SpiInstance, Spi, SpiDma, SpiDmaTransfer},
+  spi::{Error as SpiError, Mode as SpiMode},
+  time::Rate,
+};
+
+use crate::algo::{PulseEncoder, RMT_CLOCK_DIVIDER};
+#[cfg(feature = "packed-pulses")]
+use crate::PACKED_CHUNK_LEN;
+#[cfg(feature = "spi-dma")]
+use crate::algo::SPI_FREQUENCY_KHZ;
+use crate::{LEDStrip, PulseError};
+
+impl PulseEncoder for PulseCode {
+  fn new_pulse(level1: bool, length1: u16, level2: bool, length2: u16) -> Self {
+    PulseCode::new(Level::from(level1), length1, Level::from(level2), length2)
+  }
+
+  fn end_marker() -> Self {
+    PulseCode::end_marker()
+  }
+}
+
+/// How a strip's RMT channel is set up by setup_strip()
+#[derive(Copy, Clone)]
+pub struct StripConfig {
+  /// Level the data line is held at between frames, `Level::High` for an inverted data line
+  pub idle_level: Level,
+}
+
+impl Default for StripConfig {
+  fn default() -> Self {
+    Self { idle_level: Level::Low }
+  }
+}
+
+impl StripConfig {
+  /// The RMT channel config for this strip, also for changing it later with `apply_config()`.
+  /// The pulse timings are computed for RMT_CLOCK_DIVIDER, so that is always the divider.
+  pub fn tx_config(&self) -> TxChannelConfig {
+    TxChannelConfig::default()
+      .with_clk_divider(RMT_CLOCK_DIVIDER)
+      .with_idle_output_level(self.idle_level)
+      .with_idle_output(true)
+  }
+}
+
+/// Set up an RMT TX channel to drive a strip from `pin`, e.g.
+/// `setup_strip(rmt.channel0, peripherals.GPIO3, StripConfig::default())`.
+/// See docs/README.md for which pins can be used.
+pub fn setup_strip<'d>(
+  channel: impl TxChannelCreator<'d, Blocking>,
+  pin: impl PeripheralOutput<'d>,
+  config: StripConfig,
+) -> Result<Channel<'d, Blocking, Tx>, ConfigError> {
+  channel.configure_tx(pin, config.tx_config())
+}
+
+/// Send pulse data to the strip and wait for it to finish.
+/// The channel is handed back on error too, so a failed frame can be skipped or retried
+/// instead of losing the channel.
+pub fn transmit_strip<'ch>(
+  channel: Channel<'ch, Blocking, Tx>,
+  pulse_data: &[PulseCode],
+) -> Result<Channel<'ch, Blocking, Tx>, (RmtError, Channel<'ch, Blocking, Tx>)> {
+  let transaction = channel.transmit(pulse_data)?;
+  transaction.wait()
+}
+
+/// Set up SPI2 with DMA to drive a strip from `pin` (MOSI) instead of the RMT, e.g.
+/// `setup_spi_strip(peripherals.SPI2, peripherals.DMA_CH0, peripherals.GPIO3)`, then send with transmit_spi().
+/// See docs/README.md for what this needs.
+#[cfg(feature = "spi-dma")]
+pub fn setup_spi_strip<'d>(
+  spi: impl SpiInstance + 'd,
+  dma_channel: impl DmaChannelFor<AnySpi<'d>>,
+  pin: impl PeripheralOutput<'d>,
+) -> Result<SpiDma<'d, Blocking>, SpiConfigError> {
+  let config = SpiConfig::default().with_frequency(Rate::from_khz(SPI_FREQUENCY_KHZ)).with_mode(SpiMode::_0);
+  Ok(Spi::new(spi, config)?.with_mosi(pin).with_dma(dma_channel))
+}
+
+/// Errors from LEDStrip::render()
+#[derive(Debug)]
+pub enum RenderError {
+  /// The buffer passed to render() can't hold the pulse data
+  Pulse(PulseError),
+  /// The RMT peripheral failed to send the frame
+  Rmt(RmtError),
+  /// The SPI peripheral failed to start sending the frame, from transmit_spi()
+  #[cfg(feature = "spi-dma")]
+  Spi(SpiError),
+}
+
+impl LEDStrip {
+  /// Level the data line should idle at between frames: low, or high when inverted
+  pub fn idle_level(&self) -> Level {
+    Level::from(self.inverted)
+  }
+
+  /// Render one frame and send it to the strip: update_pixels(), generate_pulse_data(),
+  /// get_pulse_data() then transmit, for main loops other than the one in main.rs.
+  /// Nothing is sent if no pixels changed, unless the last frame failed to send or a refresh was requested.
+  /// `buffer` is scratch space for the pulse data, RENDER_BUFFER_LEN is always big enough.
+  /// With packed-pulses the frame is streamed through it in chunks instead, see transmit_packed().
+  /// The channel is handed back on error too, so the next call can try again.
+  pub fn render<'ch>(
+    &mut self,
+    channel: Channel<'ch, Blocking, Tx>,
+    buffer: &mut [PulseCode],
+  ) -> Result<Channel<'ch, Blocking, Tx>, (RenderError, Channel<'ch, Blocking, Tx>)> {
+    let changed = self.update_pixels();
+    let refresh = self.take_refresh();
+    if !changed && !self.transmit_failed && !refresh {
+      return Ok(channel);
+    }
+    self.generate_pulse_data();
+    // Stays set until a frame goes through, so it is sent again even if nothing changes
+    self.transmit_failed = true;
+    #[cfg(not(feature = "packed-pulses"))]
+    let channel = {
+      let pulse_data = match self.try_get_pulse_data(buffer) {
+        Ok(pulse_data) => pulse_data,
+        Err(e) => return Err((RenderError::Pulse(e), channel)),
+      };
+      transmit_strip(channel, pulse_data).map_err(|(e, channel)| (RenderError::Rmt(e), channel))?
+    };
+    #[cfg(feature = "packed-pulses")]
+    let channel = self.transmit_packed(channel, buffer)?;
+    self.transmit_failed = false;
+    Ok(channel)
+  }
+
+  /// Start sending the frame from try_get_spi_data() over SPI and return as soon as the DMA is running.
+  /// The CPU is free until the transfer's wait() hands the SPI and buffer back, so the next frame can be
+  /// rendered while this one goes out. Call generate_pulse_data() first, like for get_pulse_data().
+  /// `buffer` must hold SPI_BUFFER_LEN bytes, e.g. `dma_tx_buffer!(SPI_BUFFER_LEN)`.
+  /// The SPI and buffer are handed back on error too.
+  #[cfg(feature = "spi-dma")]
+  pub fn transmit_spi<'d>(
+    &self,
+    spi: SpiDma<'d, Blocking>,
+    mut buffer: DmaTxBuf,
+  ) -> Result<SpiDmaTransfer<'d, Blocking, DmaTxBuf>, (RenderError, SpiDma<'d, Blocking>, DmaTxBuf)> {
+    let len = match self.try_get_spi_data(buffer.as_mut_slice()) {
+      Ok(data) => data.len(),
+      Err(e) => return Err((RenderError::Pulse(e), spi, buffer)),
+    };
+    buffer.set_length(len);
+    spi.write(len, buffer).map_err(|(e, spi, buffer)| (RenderError::Spi(e), spi, buffer))
+  }
+
+  /// Send the frame from packed_chunk() one chunk at a time, expanding the next chunk into the other half
+  /// of `buffer` while the RMT sends the current one. Every chunk fits in the channel's RMT memory, so it is
+  /// all loaded when the transmit starts. The line idles for the few microseconds between chunks, which only
+  /// stretches the low part of a bit, well short of the reset time.
+  #[cfg(feature = "packed-pulses")]
+  fn transmit_packed<'ch>(
+    &self,
+    channel: Channel<'ch, Blocking, Tx>,
+    buffer: &mut [PulseCode],
+  ) -> Result<Channel<'ch, Blocking, Tx>, (RenderError, Channel<'ch, Blocking, Tx>)> {
+    if buffer.len() < 2 * PACKED_CHUNK_LEN {
+      let e = PulseError::BufferTooSmall { required: 2 * PACKED_CHUNK_LEN, provided: buffer.len() };
+      return Err((RenderError::Pulse(e), channel));
+    }
+    let (mut current, mut next) = buffer[..2 * PACKED_CHUNK_LEN].split_at_mut(PACKED_CHUNK_LEN);
+    let mut channel = channel;
+    let mut index = 0;
+    // There is always at least the reset chunk
+    let mut len = self.packed_chunk(index, current).unwrap_or(0);
+    loop {
+      let transaction = match channel.transmit(&current[..len]) {
+        Ok(transaction) => transaction,
+        Err((e, channel)) => return Err((RenderError::Rmt(e), channel)),
+      };
+      let next_len = self.packed_chunk(index + 1, next);
+      channel = transaction.wait().map_err(|(e, channel)| (RenderError::Rmt(e), channel))?;
+      let Some(next_len) = next_len else {
+        return Ok(channel);
+      };
+      core::mem::swap(&mut current, &mut next);
+      len = next_len;
+      index += 1;
+    }
+  }
+}
+
+/// Helper function to format and print elapsed time to USB serial
+/// Formats time as milliseconds with microsecond precision (e.g., "12.345ms")
+pub fn print_elapsed_time<'a, Dm: esp_hal::DriverMode>(serial: &mut esp_hal::usb_serial_jtag::UsbSerialJtagTx<'a, Dm>, elapsed: esp_hal::time::Duration) {
+  let micros = elapsed.as_micros();
+  let millis = micros / 1000;
+  let frac = micros % 1000;
+
+  // Manual formatting in no_std environment
+  let mut buffer = [0u8; 64];
+  let mut pos = 0;
+
+  // Write "Frame time: "
+  let prefix = b"Frame time: ";
+  buffer[pos..pos + prefix.len()].copy_from_slice(prefix);
+  pos += prefix.len();
+
+  // Format milliseconds
+  pos += format_u64(millis, &mut buffer[pos..]);
+
+  // Add decimal point
+  buffer[pos] = b'.';
+  pos += 1;
+
+  // Format microseconds (3 digits with leading zeros)
+  buffer[pos] = b'0' + ((frac / 100) % 10) as u8;
+  buffer[pos + 1] = b'0' + ((frac / 10) % 10) as u8;
+  buffer[pos + 2] = b'0' + (frac % 10) as u8;
+  pos += 3;
+
+  // Add "ms\n"
+  buffer[pos] = b'm';
+  buffer[pos + 1] = b's';
+  buffer[pos + 2] = b'\n';
+  pos += 3;
+
+  let _ = serial.write(&buffer[..pos]);
+}
+
+/// Helper to format a u64 into a byte buffer, returns number of bytes written
+fn format_u64(mut n: u64, buffer: &mut [u8]) -> usize {
+  if n == 0 {
+    buffer[0] = b'0';
+    return 1;
+  }
+
+  let mut temp = [0u8; 20]; // Max u64 is 20 digits
+  let mut i = 0;
+
+  while n > 0 {
+    temp[i] = b'0' + (n % 10) as u8;
+    n /= 10;
+    i += 1;
+  }
+
+  // Reverse into buffer
+  for j in 0..i {
+    buffer[j] = temp[i - 1 - j];
+  }
+
+  i
+}
@@ -1,20 +1,122 @@
 #![no_std]
 
+// algo and command are pure (no esp_hal), everything touching esp_hal goes in hal
 mod algo;
 mod command;
+#[cfg(feature = "hal")]
+mod hal;
 
-use esp_hal::rmt::PulseCode;
+use heapless::Vec;
+// Float math for no_std, the tests link std which has it built in
+#[cfg(not(test))]
 use micromath::F32Ext;
 
-use crate::algo::{hsv_to_rgb, rgb_to_pulses};
-use crate::command::SerialCommand;
+use crate::algo::{
+  MAX_TICKS_PER_PULSE, RMT_TICKS_PER_US, breath, gamma_table, life_step, rainbow_hue, reset_pulses, xorshift32,
+};
+#[cfg(feature = "packed-pulses")]
+use crate::algo::{bytes_to_pulses, color_bytes};
+use crate::command::{CommandView, SerialCommand, encode_pixels, encode_pixels_chunk, encode_status, encode_strip_info, encode_version};
 
-pub use crate::algo::print_elapsed_time;
-pub use crate::command::SerialParser;
+pub use crate::algo::{
+  ChipTiming, ColorFormat, ColorVisionMode, Easing, MAX_KELVIN, MIN_KELVIN, PulseEncoder, RMT_CLOCK_DIVIDER, RMT_SOURCE_HZ,
+  SPI_BYTES_PER_BYTE, SPI_FREQUENCY_KHZ, SPI_MIN_RESET_US, byte_to_spi, bytes_to_spi, color_vision_remap, fast_sin,
+  heat_to_color, hsv_to_rgb, kelvin_to_rgb, ns_to_ticks, palette_lookup, perceptual_to_linear, rgb_to_hsv, rgb_to_pulses,
+  rgbw_to_pulses, scale_rgb, spi_reset_bytes,
+};
+pub use crate::command::{
+  MAX_FRAME_LEN, MAX_PAYLOAD_LEN, MAX_READ_PIXELS, PARSER_BUFFER_LEN, PERF_REPORT_ACTION, PIXEL_DUMP_ACTION,
+  PIXEL_DUMP_CHUNK_PIXELS, SerialParser, Status, encode_perf,
+};
+#[cfg(feature = "hal")]
+pub use crate::hal::{RenderError, StripConfig, print_elapsed_time, setup_strip, transmit_strip};
+#[cfg(feature = "spi-dma")]
+pub use crate::hal::setup_spi_strip;
+
+/// What pulse_data holds: esp_hal's PulseCode, or the same bits in a u32 without the `hal` feature
+#[cfg(feature = "hal")]
+pub type Pulse = esp_hal::rmt::PulseCode;
+#[cfg(not(feature = "hal"))]
+pub type Pulse = u32;
 
 pub const NUM_LEDS: usize = 280;
+/// Firmware version (major, minor, patch), taken from Cargo.toml
+pub const VERSION: [u8; 3] = [
+  parse_version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+  parse_version_part(env!("CARGO_PKG_VERSION_MINOR")),
+  parse_version_part(env!("CARGO_PKG_VERSION_PATCH")),
+];
+/// Upper bound for frames_per_second. Transmitting 280 LEDs takes ~8.4ms,
+/// so anything much higher than this can't be kept up with anyway.
+pub const MAX_FRAMES_PER_SECOND: u8 = 120;
+/// Number of frames each color is shown for in StripSetting::SelfTest
+pub const SELF_TEST_FRAMES_PER_COLOR: u16 = 25;
+/// Maximum number of PulseCodes used for the reset gap before the end marker
+pub const MAX_RESET_PULSES: usize = 4;
+/// Longest reset gap that fits in MAX_RESET_PULSES
+pub const MAX_RESET_US: u16 = (MAX_RESET_PULSES as u32 * MAX_TICKS_PER_PULSE / RMT_TICKS_PER_US) as u16;
+/// Pulses budgeted per LED: 24 for GRB, or 32 with the rgbw feature so every LED can be a GRBW one
+pub const MAX_PULSES_PER_LED: usize = if cfg!(feature = "rgbw") { 32 } else { 24 };
+/// Size of the buffer needed by get_pulse_data():
+/// MAX_PULSES_PER_LED for every LED, then the reset pulses and end marker
+pub const PULSE_BUFFER_LEN: usize = NUM_LEDS * MAX_PULSES_PER_LED + MAX_RESET_PULSES + 1;
+/// Size of pulse_data with the packed-pulses feature: the bytes for every LED, 3 (GRB) or 4 (GRBW) per LED
+#[cfg(feature = "packed-pulses")]
+pub const PACKED_PULSE_LEN: usize = NUM_LEDS * MAX_PULSES_PER_LED / 8;
+/// Bytes expanded into pulses at a time by packed_chunk(), so a chunk and its end marker (41 pulses)
+/// fit in the 48 pulses of RMT memory a channel has and go out without the CPU refilling it
+#[cfg(feature = "packed-pulses")]
+pub const PACKED_CHUNK_BYTES: usize = 5;
+/// Size of one chunk from packed_chunk(): 8 pulses per byte and the end marker
+#[cfg(feature = "packed-pulses")]
+pub const PACKED_CHUNK_LEN: usize = PACKED_CHUNK_BYTES * 8 + 1;
+/// Size of the scratch buffer render() needs: the whole frame, or two chunks with packed-pulses
+#[cfg(not(feature = "packed-pulses"))]
+pub const RENDER_BUFFER_LEN: usize = PULSE_BUFFER_LEN;
+#[cfg(feature = "packed-pulses")]
+pub const RENDER_BUFFER_LEN: usize = 2 * PACKED_CHUNK_LEN;
+/// Size of the buffer try_get_spi_data() needs: every LED's bytes as SPI bytes and the longest reset
+#[cfg(feature = "packed-pulses")]
+pub const SPI_BUFFER_LEN: usize = PACKED_PULSE_LEN * SPI_BYTES_PER_BYTE + spi_reset_bytes(MAX_RESET_US);
+/// Maximum number of color format segments, see LEDStrip::set_segment_format()
+pub const MAX_SEGMENTS: usize = 8;
+/// Maximum number of LEDs the sparkle overlay lights in one frame
+pub const MAX_SPARKLES: usize = 32;
+/// Brightness change per step of brightness_up() and brightness_down() without a step size
+pub const BRIGHTNESS_STEP: f32 = 0.01;
+/// brightness_down() stops here so the strip never goes fully dark from stepping down
+pub const MIN_STEP_BRIGHTNESS: f32 = 0.01;
+/// brightness_up() stops here
+pub const MAX_STEP_BRIGHTNESS: f32 = 1.0;
+/// Maximum number of color stops in StripSetting::Gradient
+pub const MAX_GRADIENT_STOPS: usize = 4;
+/// Maximum number of pixels in the scroll framebuffer, which can be wider than the strip
+pub const MAX_SCROLL_PIXELS: usize = 1024;
+/// Maximum number of waves in StripSetting::Wave
+pub const MAX_WAVES: u8 = 4;
+/// Wavelength of each wave in StripSetting::Wave, as waves along the strip
+const WAVE_CYCLES: [f32; MAX_WAVES as usize] = [3.0, 5.0, 2.0, 7.0];
+/// Speed of each wave in StripSetting::Wave, in strip lengths per phase cycle. Negative moves backwards.
+/// Whole numbers, so every wave is back where it started when the phase wraps around.
+const WAVE_SPEEDS: [f32; MAX_WAVES as usize] = [1.0, -2.0, 3.0, -1.0];
+/// Size of the blob written by config_snapshot(), including the CRC
+pub const CONFIG_SNAPSHOT_LEN: usize = 52;
+/// Layout version of the config snapshot, bump when the layout changes
+/// so old snapshots are rejected instead of misread
+const CONFIG_SNAPSHOT_VERSION: u8 = 0x04;
+
+const fn parse_version_part(part: &str) -> u8 {
+  let bytes = part.as_bytes();
+  let mut value = 0u8;
+  let mut i = 0;
+  while i < bytes.len() {
+    value = value * 10 + (bytes[i] - b'0');
+    i += 1;
+  }
+  value
+}
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub struct RGBPixel {
   pub r: u8,
   pub g: u8,
@@ -41,42 +143,365 @@ impl RGBPixel {
   pub const fn green() -> Self {
     Self { r: 0, g: 255, b: 0 }
   }
+
+  pub const fn white() -> Self {
+    Self { r: 255, g: 255, b: 255 }
+  }
+
+  /// Add two colors channel by channel, clamping at 255, e.g. for drawing an overlay on top of a setting
+  pub const fn saturating_add(&self, other: &RGBPixel) -> RGBPixel {
+    RGBPixel {
+      r: self.r.saturating_add(other.r),
+      g: self.g.saturating_add(other.g),
+      b: self.b.saturating_add(other.b),
+    }
+  }
+
+  /// Subtract `other` channel by channel, clamping at 0
+  pub const fn saturating_sub(&self, other: &RGBPixel) -> RGBPixel {
+    RGBPixel {
+      r: self.r.saturating_sub(other.r),
+      g: self.g.saturating_sub(other.g),
+      b: self.b.saturating_sub(other.b),
+    }
+  }
+
+  /// Scale every channel by `factor`, clamped to 0-255 and rounded down (a factor of 0.5 turns 200 into 100).
+  pub fn scale(&self, factor: f32) -> RGBPixel {
+    let scale = |c: u8| (c as f32 * factor).clamp(0.0, 255.0) as u8;
+    RGBPixel {
+      r: scale(self.r),
+      g: scale(self.g),
+      b: scale(self.b),
+    }
+  }
+
+  /// Blend from this color (`t` = 0.0) to `other` (`t` = 1.0), `t` is clamped to that range.
+  /// Channels are rounded to nearest with halves rounded up, so the endpoints come back exactly
+  /// and halfway between off and white is (128, 128, 128).
+  pub fn lerp(&self, other: &RGBPixel, t: f32) -> RGBPixel {
+    let t = t.clamp(0.0, 1.0);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t + 0.5) as u8;
+    RGBPixel {
+      r: lerp(self.r, other.r),
+      g: lerp(self.g, other.g),
+      b: lerp(self.b, other.b),
+    }
+  }
+
+  /// Set this pixel to `new`, returning true if that changed it
+  pub fn set_if_changed(&mut self, new: RGBPixel) -> bool {
+    if *self == new {
+      return false;
+    }
+    *self = new;
+    true
+  }
 }
 
 #[derive(Copy, Clone)]
 pub enum StripSetting {
   Custom,
-  Breathing { r: u8, g: u8, b: u8 },
+  /// The color breathing between `min` and `max` (0-255 of the global brightness) along the breathing curve.
+  /// A `min` above 0 keeps the strip from turning fully off, `min` == `max` is a steady color.
+  Breathing { r: u8, g: u8, b: u8, min: u8, max: u8 },
   SolidColor { r: u8, g: u8, b: u8 },
   /// Rainbow cycle animation. `cycles` defines how many full rainbow cycles
-  /// appear across the entire strip length (e.g., 1.0 = one rainbow, 2.0 = two rainbows)
-  RainbowCycle { cycles: f32 },
+  /// appear across the entire strip length (e.g., 1.0 = one rainbow, 2.0 = two rainbows).
+  /// `sat` and `val` are passed to hsv_to_rgb, lower them for pastel or dimmed rainbows.
+  RainbowCycle { cycles: f32, sat: u8, val: u8 },
+  /// Rainbow cycle where only every `spacing`-th LED is lit, and the lit LEDs
+  /// move along the strip with the animation phase
+  RainbowChase { cycles: f32, spacing: u8 },
+  /// A ring of color expanding outwards from `origin` and fading as it travels,
+  /// restarting every phase cycle. `origin` is scaled from 0-255 onto the strip length.
+  Ripple { r: u8, g: u8, b: u8, origin: u8 },
+  /// Alternating blocks of two colors, `block` LEDs wide, scrolling along the strip.
+  /// One phase cycle scrolls by one pair of blocks.
+  Marquee { r1: u8, g1: u8, b1: u8, r2: u8, g2: u8, b2: u8, block: u8 },
+  /// Breathing where each channel breathes with its own phase offset,
+  /// in 1/256ths of a cycle. Equal offsets look the same as Breathing.
+  BreathingRGB { r: u8, g: u8, b: u8, offset_r: u8, offset_g: u8, offset_b: u8 },
+  /// Rainbow (same as RainbowCycle) swept into the strip from the start, then held still.
+  /// Reveals phase_step of the strip per frame.
+  RainbowSweep { cycles: f32 },
+  /// Show red, green, blue then white across the whole strip, each for
+  /// SELF_TEST_FRAMES_PER_COLOR frames, then switch to Custom with all LEDs off.
+  /// Used to check wiring and color order.
+  SelfTest,
+  /// Move every pixel towards the target color by at most `rate` per channel per frame,
+  /// starting from whatever is currently displayed
+  FadeTo { r: u8, g: u8, b: u8, rate: u8 },
+  /// Scroll a NUM_LEDS wide window across the scroll framebuffer, wrapping around at its end.
+  /// Passes over the whole framebuffer `speed` times per phase cycle,
+  /// interpolating between pixels for positions in between.
+  Scroll { speed: f32 },
+  /// A comet travelling along the strip once per phase cycle, with a tail of `tail` LEDs fading out behind it.
+  /// Each LED takes the rainbow hue of its position (`cycles` rainbows along the strip),
+  /// so the head changes color as it moves and the tail shows the colors it went through.
+  RainbowComet { cycles: f32, tail: u8 },
+  /// `count` sine waves of brightness (at most MAX_WAVES) with different wavelengths and speeds
+  /// moving over the strip in one color. The waves are summed and clamped, so they pile up into
+  /// bright crests where they meet. A single wave is plain running lights.
+  Wave { r: u8, g: u8, b: u8, count: u8 },
+  /// Still gradient from `start_hue` at the first LED to `end_hue` at the last (degrees), going the
+  /// shorter way around the color wheel, so 350 to 10 passes through red rather than the whole rainbow.
+  HueGradient { start_hue: u16, end_hue: u16, sat: u8, val: u8 },
+  /// 1D cellular automaton with Wolfram rule `rule` (e.g. 90, 30, 110), one generation every `frames` frames.
+  /// Live cells show the color, dead ones are off. Starts from random cells, and starts over
+  /// from random cells if they all die.
+  Life { r: u8, g: u8, b: u8, rule: u8, frames: u8 },
+  /// Still gradient through the first `count` of `stops` (at most MAX_GRADIENT_STOPS), each a position
+  /// (0 is the first LED, 255 the last) and a color. Before the first stop and after the last, LEDs take its color.
+  Gradient { stops: [(u8, RGBPixel); MAX_GRADIENT_STOPS], count: u8 },
+  /// All LEDs off, as a setting rather than turning the strip off with set_on().
+  /// Unlike set_on(false) this ignores the off mode and idle color, and the strip stays on.
+  Off,
+}
+
+/// Errors from getting pulse data out of an LEDStrip
+#[derive(Copy, Clone, Debug)]
+pub enum PulseError {
+  /// The provided buffer can't hold the pulse data, `required` PulseCodes are needed
+  BufferTooSmall { required: usize, provided: usize },
+}
+
+/// Why LEDStrip::apply_command() didn't apply a command
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CommandError {
+  /// The action isn't one LEDStrip handles
+  UnknownAction(u8),
+  /// Set value with a value ID that doesn't exist
+  UnknownValue(u8),
+  /// Set StripSetting or host loss fallback with a setting ID that doesn't exist
+  UnknownSetting(u8),
+  /// A mode, overlay, curve, format or chip ID that doesn't exist
+  UnknownOption(u8),
+  /// The first LED index is past the end of the strip (and manual input doesn't wrap around)
+  IndexOutOfRange(usize),
+  /// Set value or StripSetting with an f32 that is NaN, infinite or out of range, with the value or setting ID
+  InvalidNumber(u8),
+}
+
+/// Effect composited on top of whatever the StripSetting renders
+#[derive(Copy, Clone)]
+pub enum Overlay {
+  /// Flash random LEDs white for one frame. Each LED has a `rate`/256 chance per frame.
+  /// The white is scaled by the global brightness, like the StripSettings are.
+  Sparkle { rate: u8 },
+}
+
+/// What the strip does when turned off
+#[derive(Copy, Clone)]
+pub enum OffMode {
+  /// Switch to the idle color immediately, then stop transmitting until turned back on
+  Clear,
+  /// Fade to the idle color over `frames` frames, then stop transmitting until turned back on
+  Fade { frames: u8 },
+  /// Keep displaying the last frame, without rendering or transmitting anything
+  KeepLastFrame,
+}
+
+/// Which post-processing stages run. They always run in this order:
+/// global brightness (with dither) in update_pixels(), then in generate_pulse_data()
+/// HSV adjust, brightness profile, mask, gamma, channel floor and the color format of the segment.
+/// The mask can't be turned off here, it does nothing while all 255.
+/// A stage that is on but set to do nothing (e.g. gamma 1.0) is skipped anyway.
+#[derive(Copy, Clone, PartialEq)]
+pub struct RenderPipeline {
+  /// Dither the global brightness over frames, see LEDStrip::set_dither_brightness()
+  pub dither: bool,
+  /// Hue shift, saturation and value
+  pub hsv_adjust: bool,
+  pub brightness_profile: bool,
+  pub gamma: bool,
+  pub channel_floor: bool,
+}
+
+impl RenderPipeline {
+  /// Everything but dither, which changes the output every frame
+  pub const fn new() -> Self {
+    Self { dither: false, hsv_adjust: true, brightness_profile: true, gamma: true, channel_floor: true }
+  }
+
+  /// One bit per stage, in pipeline order from bit 0: dither, HSV adjust, brightness profile, gamma, channel floor
+  pub const fn from_bits(bits: u8) -> Self {
+    Self {
+      dither: bits & 0b00001 != 0,
+      hsv_adjust: bits & 0b00010 != 0,
+      brightness_profile: bits & 0b00100 != 0,
+      gamma: bits & 0b01000 != 0,
+      channel_floor: bits & 0b10000 != 0,
+    }
+  }
+
+  pub const fn to_bits(self) -> u8 {
+    self.dither as u8
+      | (self.hsv_adjust as u8) << 1
+      | (self.brightness_profile as u8) << 2
+      | (self.gamma as u8) << 3
+      | (self.channel_floor as u8) << 4
+  }
+}
+
+impl Default for RenderPipeline {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 pub struct LEDStrip {
   /// Whether update_pixels() should render anything
   is_on: bool,
+  /// What update_pixels() does while the strip is off
+  off_mode: OffMode,
+  /// Frames left until fully faded out, for OffMode::Fade
+  fade_frames_left: u8,
+  /// Frames to ramp up from off to full brightness after turning on, 0 for instant on
+  soft_start_frames: u16,
+  /// Frames left in the soft start ramp, counted down in update_pixels()
+  soft_start_left: u16,
+  /// Color shown while the strip is off, e.g. a dim warm white for standby
+  idle_color: RGBPixel,
   /// Buffer holding the RGB values for each LED
   pixels: [RGBPixel; NUM_LEDS],
+  /// Buffer holding (hue, saturation, value) for each LED, only used in HSV mode.
+  /// Costs an extra 4 bytes per LED on top of `pixels`.
+  hsv_pixels: [(u16, u8, u8); NUM_LEDS],
+  /// Whether Custom renders `pixels` from `hsv_pixels` (set by manual HSV input)
+  hsv_mode: bool,
   /// Buffer holding the RMT pulse data for the entire strip
-  pulse_data: [PulseCode; NUM_LEDS * 24 + 1],
+  /// The reset pulses and end marker come after the pulses for the last LED, at led_pulse_count.
+  #[cfg(not(feature = "packed-pulses"))]
+  pulse_data: [Pulse; PULSE_BUFFER_LEN],
+  /// The bytes sent to each LED, turned into pulses (8 per byte) only in try_get_pulse_data().
+  /// Pulse index `p` is bit `p % 8` of byte `p / 8`, there are no reset pulses or end marker.
+  #[cfg(feature = "packed-pulses")]
+  pulse_data: [u8; PACKED_PULSE_LEN],
+  /// Number of pulses for all LEDs in pulse_data, depends on the segment formats
+  #[cfg(not(feature = "packed-pulses"))]
+  led_pulse_count: usize,
+  /// Bit timing of the LED chips, applied in generate_pulse_data()
+  chip_timing: ChipTiming,
+  /// Color format segments as (first LED, format), sorted by first LED.
+  /// Each runs until the next one, LEDs before the first segment are GRB.
+  segments: Vec<(usize, ColorFormat), MAX_SEGMENTS>,
+  /// Extra time (in microseconds) to hold the line low before the end marker
+  reset_us: u16,
+  /// Number of reset pulses in pulse_data, written by generate_pulse_data()
+  reset_pulse_count: usize,
   /// Setting for rendering pixels in update_pixels()
   setting: StripSetting,
   /// Global brightness level, applied in update_pixels().
   /// Can be anything above 0.0, above 1.0 to brighten further.
   brightness: f32,
+  /// Treat brightness as perceived brightness and map it through perceptual_to_linear()
+  perceptual_brightness: bool,
+  /// What the channels are actually multiplied by, brightness after the brightness curve.
+  /// Only set in update_brightness_factor()
+  brightness_factor: f32,
   /// Phase counter for animations, ranges from 0.0 to 1.0 per cycle
   phase: f32,
   /// How much to increment phase per update (speed of animation)
   phase_step: f32,
+  /// Animation speed in cycles per second, if set with set_animation_hz().
+  /// phase_step is recomputed from this when frames_per_second changes.
+  animation_hz: Option<f32>,
+  /// How much RainbowCycle's phase step swings around phase_step, 0.0 (off) to 1.0 (stops at the slowest)
+  speed_modulation_depth: f32,
+  /// Frames per speed up and slow down cycle of RainbowCycle
+  speed_modulation_period: u16,
+  /// Position in the speed modulation cycle, in frames
+  speed_modulation_frame: u16,
   /// Number of LEDs to update when filling pulse data
   num_leds_to_update: usize,
+  /// First pixel sent when filling pulse data, the strip shows
+  /// pixels update_start..update_start + num_leds_to_update
+  update_start: usize,
   /// Number of update + write to RMT per second
   frames_per_second: u8,
   /// Whether to reverse the animation direction (subtract from phase instead of add)
   reverse_animation: bool,
+  /// Whether RainbowChase and Marquee move backwards, separate from reverse_animation
+  chase_reverse: bool,
+  /// Added to (or subtracted from) phase for the chase settings, so turning around doesn't jump
+  chase_phase: f32,
+  /// Fixed number of LEDs the chase settings are shifted along the strip
+  chase_start: u16,
+  /// Number of LEDs actually on the strip, animations are spread over these and only these are sent
+  active_len: usize,
+  /// Per-LED brightness (255 = full) multiplied onto each pixel in generate_pulse_data()
+  mask: [u8; NUM_LEDS],
+  /// Whether any mask value is below 255, so generate_pulse_data() can skip the mask
+  mask_active: bool,
+  /// Whether animations are paused, see set_frozen()
+  frozen: bool,
+  /// Whether manual color input past the end of the strip wraps around to the start
+  /// instead of being dropped (useful for rings)
+  wrap_manual_input: bool,
   /// Whether anything other than settings has been changed over commands
   raw_value_changed: bool,
+  /// Frames rendered since the current setting was set (saturates)
+  setting_frame: u16,
+  /// Number of calls to update_pixels() since startup (wraps around)
+  frame_count: u64,
+  /// Effect applied on top of the setting in update_pixels()
+  overlay: Option<Overlay>,
+  /// LEDs changed by the overlay last frame and their colors before that,
+  /// restored at the start of the next frame so the setting shows through again
+  overlay_restore: Vec<(usize, RGBPixel), MAX_SPARKLES>,
+  /// Cells for StripSetting::Life, one per LED, true is alive
+  cells: [bool; NUM_LEDS],
+  /// State for xorshift32, used by random effects
+  rng_state: u32,
+  /// Degrees (0-359) to rotate the hue of every pixel by in generate_pulse_data()
+  hue_shift: u16,
+  /// Multiplier for the saturation of every pixel in generate_pulse_data()
+  saturation: f32,
+  /// Multiplier for the value (HSV) of every pixel in generate_pulse_data()
+  value: f32,
+  /// Brightness multiplier at the first LED, interpolated towards brightness_end
+  /// along the strip in generate_pulse_data()
+  brightness_start: f32,
+  /// Brightness multiplier at the last LED
+  brightness_end: f32,
+  /// Gamma exponent the gamma table was computed with
+  gamma: f32,
+  /// Lookup table for gamma correction, applied to every channel in generate_pulse_data().
+  /// Recomputed only in set_gamma().
+  gamma_lut: [u8; 256],
+  /// Lowest non-zero value a channel is sent as, dimmer channels flicker on some strips
+  channel_floor: u8,
+  /// Curve Breathing and BreathingRGB follow
+  breathing_easing: Easing,
+  /// Whether brightness above 1.0 scales colors down to keep their hue instead of clipping channels
+  preserve_hue: bool,
+  /// Per channel multipliers (255 = 1.0) for the white part of SolidColor, so white looks neutral
+  white_balance: RGBPixel,
+  /// Post-processing stages that are turned on, including dithering the global brightness
+  pipeline: RenderPipeline,
+  /// Color remap for color vision deficiencies, applied in generate_pulse_data() after HSV adjust
+  color_vision: ColorVisionMode,
+  /// Offset added before rounding when applying the global brightness, changes every frame
+  /// while pipeline.dither is set and stays 0.0 otherwise
+  dither: f32,
+  /// Framebuffer for StripSetting::Scroll, uploaded in chunks with the scroll buffer command
+  scroll_buffer: [RGBPixel; MAX_SCROLL_PIXELS],
+  /// Number of pixels in scroll_buffer in use
+  scroll_len: usize,
+  /// Milliseconds without a command before switching to host_fallback, 0 to never time out
+  host_timeout_ms: u32,
+  /// Setting to switch to when the host is lost, None to turn the strip off
+  host_fallback: Option<StripSetting>,
+  /// Whether the fallback has been applied since the last command
+  host_lost: bool,
+  /// Whether the last frame render() tried to send failed, so it gets sent again
+  #[cfg(feature = "hal")]
+  transmit_failed: bool,
+  /// Whether the next frame has to be sent even if nothing changed, see request_refresh()
+  force_refresh: bool,
+  /// Whether the data line is inverted (idle high), for inverting level shifters
+  inverted: bool,
 }
 
 impl Default for LEDStrip {
@@ -89,273 +514,3048 @@ impl LEDStrip {
   pub fn new() -> Self {
     Self {
       is_on: true,
+      off_mode: OffMode::Clear,
+      fade_frames_left: 0,
+      soft_start_frames: 0,
+      soft_start_left: 0,
+      idle_color: RGBPixel::off(),
       pixels: [RGBPixel::off(); NUM_LEDS],
-      pulse_data: [PulseCode::default(); NUM_LEDS * 24 + 1],
+      hsv_pixels: [(0, 0, 0); NUM_LEDS],
+      hsv_mode: false,
+      #[cfg(not(feature = "packed-pulses"))]
+      pulse_data: [Pulse::default(); PULSE_BUFFER_LEN],
+      #[cfg(feature = "packed-pulses")]
+      pulse_data: [0; PACKED_PULSE_LEN],
+      reset_us: 0,
+      reset_pulse_count: 0,
+      #[cfg(not(feature = "packed-pulses"))]
+      led_pulse_count: NUM_LEDS * 24,
+      segments: Vec::new(),
+      chip_timing: ChipTiming::Ws2812b,
       setting: StripSetting::Custom,
       brightness: 0.05,
+      perceptual_brightness: false,
+      brightness_factor: 0.05,
       phase: 0.0,
       phase_step: 0.01,
+      animation_hz: None,
+      speed_modulation_depth: 0.0,
+      speed_modulation_period: 1,
+      speed_modulation_frame: 0,
       num_leds_to_update: NUM_LEDS,
+      update_start: 0,
       frames_per_second: 25,
       reverse_animation: false,
+      chase_reverse: false,
+      chase_phase: 0.0,
+      chase_start: 0,
+      active_len: NUM_LEDS,
+      mask: [255; NUM_LEDS],
+      mask_active: false,
+      frozen: false,
+      wrap_manual_input: false,
       raw_value_changed: false,
+      setting_frame: 0,
+      frame_count: 0,
+      overlay: None,
+      overlay_restore: Vec::new(),
+      cells: [false; NUM_LEDS],
+      rng_state: 0x2545_F491,
+      hue_shift: 0,
+      saturation: 1.0,
+      value: 1.0,
+      brightness_start: 1.0,
+      brightness_end: 1.0,
+      gamma: 1.0,
+      gamma_lut: core::array::from_fn(|i| i as u8),
+      channel_floor: 0,
+      breathing_easing: Easing::Sine,
+      preserve_hue: false,
+      white_balance: RGBPixel::white(),
+      pipeline: RenderPipeline::new(),
+      color_vision: ColorVisionMode::None,
+      dither: 0.0,
+      scroll_buffer: [RGBPixel::off(); MAX_SCROLL_PIXELS],
+      scroll_len: 0,
+      host_timeout_ms: 0,
+      host_fallback: None,
+      host_lost: false,
+      #[cfg(feature = "hal")]
+      transmit_failed: false,
+      force_refresh: false,
+      inverted: false,
     }
   }
 
+  pub fn get_frame_count(&self) -> u64 {
+    self.frame_count
+  }
+
   pub fn get_frames_per_second(&self) -> u8 {
     self.frames_per_second
   }
 
+  /// Set frames per second, clamped to 1..=MAX_FRAMES_PER_SECOND.
+  /// 0 would make the frame duration infinite and stall the main loop.
+  pub fn set_frames_per_second(&mut self, fps: u8) {
+    self.frames_per_second = fps.clamp(1, MAX_FRAMES_PER_SECOND);
+    // Keep the animation speed the same at the new frame rate
+    if let Some(hz) = self.animation_hz {
+      self.set_animation_hz(hz);
+    }
+  }
+
   fn set_pixel(&mut self, index: usize, pixel: RGBPixel) {
     if index < NUM_LEDS {
       self.pixels[index] = pixel;
     }
   }
 
+  /// Set a pixel with the index wrapped around the strip, for rings:
+  /// -1 is the last LED and NUM_LEDS is the first. Switches to Custom so it stays set.
+  pub fn set_pixel_wrapped(&mut self, index: i32, pixel: RGBPixel) {
+    self.set_setting(StripSetting::Custom);
+    let index = index.rem_euclid(NUM_LEDS as i32) as usize;
+    self.set_pixel(index, pixel);
+    self.raw_value_changed = true;
+  }
+
+  /// Rotate every pixel `offset` LEDs towards the end of the strip (negative towards the start),
+  /// wrapping around. Doesn't change the setting, so an animation will redraw over it next frame.
+  pub fn shift(&mut self, offset: i32) {
+    let offset = offset.rem_euclid(NUM_LEDS as i32) as usize;
+    self.pixels.rotate_right(offset);
+    // Custom redraws pixels from these in HSV mode, so they have to move too
+    self.hsv_pixels.rotate_right(offset);
+    self.raw_value_changed = true;
+  }
+
+  /// Write manual color input (RGB triplets) starting at `start_index`
+  fn write_manual_rgb(&mut self, start_index: usize, color_data: &[u8]) {
+    for (i, rgb) in color_data.chunks_exact(3).enumerate() {
+      let Some(led_index) = self.manual_input_index(start_index + i) else {
+        break; // Don't exceed strip bounds
+      };
+      self.set_pixel(led_index, RGBPixel::new(rgb[0], rgb[1], rgb[2]));
+    }
+  }
+
+  pub fn get_active_len(&self) -> usize {
+    self.active_len
+  }
+
+  /// Set how many LEDs are physically on the strip (1 to NUM_LEDS) for strips shorter than NUM_LEDS.
+  /// Animations are spread over that many LEDs (e.g. one rainbow across 60 LEDs instead of 280),
+  /// and only they are rendered and sent. The pixels past it are turned off.
+  pub fn set_active_len(&mut self, len: usize) {
+    self.active_len = len.clamp(1, NUM_LEDS);
+    for pixel in self.pixels[self.active_len..].iter_mut() {
+      *pixel = RGBPixel::off();
+    }
+    self.raw_value_changed = true;
+  }
+
+  /// Set mask values (0 = off, 255 = full brightness) for the LEDs from `start_index`, dropping any past
+  /// the end of the strip. The mask scales each pixel after the setting has rendered it, so it dims
+  /// zones of any setting. All 255 by default.
+  pub fn set_mask(&mut self, start_index: usize, values: &[u8]) {
+    let start_index = start_index.min(NUM_LEDS);
+    let len = values.len().min(NUM_LEDS - start_index);
+    self.mask[start_index..start_index + len].copy_from_slice(&values[..len]);
+    self.mask_active = self.mask.iter().any(|&m| m != 255);
+  }
+
+  /// Set `count` LEDs starting at `start_index` to one color.
+  /// Stops at the end of the strip, or wraps around like manual input if enabled.
+  fn fill_range(&mut self, start_index: usize, count: usize, pixel: RGBPixel) {
+    for i in 0..count.min(NUM_LEDS) {
+      let Some(led_index) = self.manual_input_index(start_index + i) else {
+        break; // Don't exceed strip bounds
+      };
+      self.set_pixel(led_index, pixel);
+    }
+  }
+
+  /// Start StripSetting::SelfTest from the beginning
+  pub fn start_self_test(&mut self) {
+    self.set_setting(StripSetting::SelfTest);
+  }
+
   fn set_setting(&mut self, setting: StripSetting) {
     self.setting = setting;
+    self.setting_frame = 0;
+    if let StripSetting::Life { .. } = setting {
+      self.seed_life();
+    }
+    // Back to RGB mode, manual HSV input turns it back on
+    self.hsv_mode = false;
+  }
+
+  /// Give every cell for StripSetting::Life a 50/50 chance of being alive
+  fn seed_life(&mut self) {
+    for cell in self.cells.iter_mut() {
+      *cell = xorshift32(&mut self.rng_state) & 1 != 0;
+    }
+  }
+
+  fn set_hsv_pixel(&mut self, index: usize, hsv: (u16, u8, u8)) {
+    if index < NUM_LEDS {
+      self.hsv_pixels[index] = hsv;
+    }
   }
 
   fn set_brightness(&mut self, brightness: f32) {
     self.brightness = brightness;
+    self.update_brightness_factor();
+  }
+
+  /// Choose whether brightness is linear (default, the factor channels are multiplied by)
+  /// or perceptual (0.5 looks half as bright as 1.0). Brightness up / down steps are even either way,
+  /// but in perceptual mode they also look even.
+  pub fn set_perceptual_brightness(&mut self, perceptual: bool) {
+    self.perceptual_brightness = perceptual;
+    self.update_brightness_factor();
+  }
+
+  /// The brightness curve, every setting uses brightness_factor so this is the only place it's applied
+  fn update_brightness_factor(&mut self) {
+    self.brightness_factor = if self.perceptual_brightness {
+      perceptual_to_linear(self.brightness)
+    } else {
+      self.brightness
+    };
+  }
+
+  pub fn get_brightness(&self) -> f32 {
+    self.brightness
+  }
+
+  /// Raise brightness by `step`, up to MAX_STEP_BRIGHTNESS.
+  /// Brightness already above that (set directly) is left alone.
+  pub fn brightness_up(&mut self, step: f32) {
+    let max = MAX_STEP_BRIGHTNESS.max(self.brightness);
+    self.set_brightness((self.brightness + step).min(max));
   }
 
+  /// Lower brightness by `step`, down to MIN_STEP_BRIGHTNESS.
+  /// Brightness already below that (set directly) is left alone.
+  pub fn brightness_down(&mut self, step: f32) {
+    let min = MIN_STEP_BRIGHTNESS.min(self.brightness);
+    self.set_brightness((self.brightness - step).max(min));
+  }
+
+  /// NaN and infinity are ignored, like in set_phase()
   fn set_phase_step(&mut self, fpc: f32) {
-    self.phase_step = fpc;
+    if fpc.is_finite() {
+      self.phase_step = fpc;
+      self.animation_hz = None;
+    }
+  }
+
+  /// Make RainbowCycle speed up and slow down along a sine, once every `period` frames (0 is treated as 1).
+  /// The phase step swings between `phase_step * (1 - depth)` and `phase_step * (1 + depth)`,
+  /// so the average speed stays the same. `depth` is clamped to 0.0 (off, the default) to 1.0.
+  pub fn set_speed_modulation(&mut self, depth: f32, period: u16) {
+    self.speed_modulation_depth = depth.clamp(0.0, 1.0);
+    self.speed_modulation_period = period.max(1);
+    self.speed_modulation_frame = 0;
+  }
+
+  /// Set animation speed in cycles per second, independent of frames_per_second.
+  /// Overrides phase_step until set_phase_step() is called.
+  pub fn set_animation_hz(&mut self, hz: f32) {
+    self.phase_step = hz / self.frames_per_second as f32;
+    self.animation_hz = Some(hz);
   }
 
   fn set_reverse_animation(&mut self, reverse: bool) {
     self.reverse_animation = reverse;
   }
 
-  fn clear(&mut self) -> bool {
-    let mut changed = false;
-    for pixel in self.pixels.iter_mut() {
-      if pixel.r != 0 || pixel.g != 0 || pixel.b != 0 {
-        changed = true;
-        *pixel = RGBPixel::off();
-      }
+  /// Set the direction and start offset (in LEDs) of RainbowChase and Marquee.
+  /// Changing direction turns the chase around where it is instead of jumping.
+  pub fn set_chase(&mut self, reverse: bool, start: u16) {
+    if reverse != self.chase_reverse {
+      let current = self.get_chase_phase();
+      self.chase_phase = if reverse { current + self.phase } else { current - self.phase };
+      self.chase_reverse = reverse;
     }
-    changed
+    self.chase_start = start;
   }
 
-  // Return a slice from the same one as the input buffer because if the buffer is bigger than necessary,
-  // only the first part should be sent.
-  // The last PulseCode needs to be the end marker.
-
-  /// Wrapper to get pulse data based on num_leds_to_update
-  pub fn get_pulse_data<'a>(&self, buffer: &'a mut [PulseCode]) -> &'a [PulseCode] {
-    if self.num_leds_to_update >= NUM_LEDS {
-      self.get_pulse_data_all(buffer)
+  /// Phase the chase settings are drawn at, in 0..1
+  fn get_chase_phase(&self) -> f32 {
+    let phase = if self.chase_reverse {
+      self.chase_phase - self.phase
     } else {
-      self.get_pulse_data_limited(self.num_leds_to_update, buffer)
-    }
+      self.chase_phase + self.phase
+    };
+    // rem_euclid keeps it positive when going backwards
+    phase.rem_euclid(1.0)
   }
 
-  /// Copy pulse data into the provided buffer.
-  fn get_pulse_data_all<'a>(&self, buffer: &'a mut [PulseCode]) -> &'a [PulseCode] {
-    if buffer.len() < self.pulse_data.len() {
-      panic!("Buffer too small for pulse data");
-    }
-    buffer.copy_from_slice(&self.pulse_data);
-    &buffer[..self.pulse_data.len()]
+  /// Set whether manual color input wraps around past the end of the strip (default: false, dropped)
+  pub fn set_wrap_manual_input(&mut self, wrap: bool) {
+    self.wrap_manual_input = wrap;
   }
 
-  /// Copy pulse data for `num` LEDs into the provided buffer.
-  /// Adds end marker after the specified number of LEDs.
-  fn get_pulse_data_limited<'a>(&self, num: usize, buffer: &'a mut [PulseCode]) -> &'a [PulseCode] {
-    let len = if num <= NUM_LEDS { num } else { NUM_LEDS };
-    let required_len = len * 24 + 1;
-    if buffer.len() < required_len {
-      panic!("Buffer too small for limited pulse data");
+  /// Index to write manual input at, or None if it's past the end of the strip and not wrapping
+  fn manual_input_index(&self, index: usize) -> Option<usize> {
+    if index < NUM_LEDS {
+      Some(index)
+    } else if self.wrap_manual_input {
+      Some(index % NUM_LEDS)
+    } else {
+      None
     }
-    buffer[..len * 24].copy_from_slice(&self.pulse_data[..len * 24]);
-    buffer[len * 24] = PulseCode::end_marker();
-    &buffer[..required_len]
   }
 
-  /// Write pulse data for all LEDs into the internal buffer.
-  pub fn generate_pulse_data(&mut self) {
-    for (i, pixel) in self.pixels.iter().enumerate() {
-      rgb_to_pulses(pixel, &mut self.pulse_data[i * 24..(i + 1) * 24]);
-    }
-    self.pulse_data[NUM_LEDS * 24] = PulseCode::end_marker();
+  /// Set (or remove with None) the effect applied on top of the current setting
+  pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+    self.overlay = overlay;
   }
 
-  /// Compute new pixel values based on the current setting and update internal pixel buffer.
+  /// Undo what the overlay drew last frame.
   /// Returns true if any pixel values were changed.
-  pub fn update_pixels(&mut self) -> bool {
-    let mut changed = self.raw_value_changed;
-    self.raw_value_changed = false;
-
-    if !self.is_on {
-      changed |= self.clear();
-      return changed;
+  fn restore_overlay(&mut self) -> bool {
+    let changed = !self.overlay_restore.is_empty();
+    for (index, pixel) in self.overlay_restore.iter() {
+      self.pixels[*index] = *pixel;
     }
-    match self.setting {
-      StripSetting::Breathing { r, g, b } => {
-        // Calculate brightness factor using sine wave
-        let brightness_factor = (0.5 + 0.5 * (self.phase * core::f32::consts::TAU).sin()) * self.brightness;
-        let new_r = ((r as f32 * brightness_factor).clamp(0.0, 255.0)) as u8;
-        let new_g = ((g as f32 * brightness_factor).clamp(0.0, 255.0)) as u8;
-        let new_b = ((b as f32 * brightness_factor).clamp(0.0, 255.0)) as u8;
-        for pixel in self.pixels.iter_mut() {
-          if pixel.r != new_r || pixel.g != new_g || pixel.b != new_b {
-            changed = true;
-            pixel.r = new_r;
-            pixel.g = new_g;
-            pixel.b = new_b;
-          }
-        }
-      }
-      StripSetting::SolidColor { r, g, b } => {
-        for pixel in self.pixels.iter_mut() {
-          let new_r = ((r as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          let new_g = ((g as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          let new_b = ((b as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          if pixel.r != new_r || pixel.g != new_g || pixel.b != new_b {
-            changed = true;
-            pixel.r = new_r;
-            pixel.g = new_g;
-            pixel.b = new_b;
+    self.overlay_restore.clear();
+    changed
+  }
+
+  /// Draw the overlay on top of the pixels rendered by the setting.
+  /// Returns true if any pixel values were changed.
+  fn apply_overlay(&mut self) -> bool {
+    match self.overlay {
+      Some(Overlay::Sparkle { rate }) => {
+        let white = ((255.0 * self.brightness_factor).clamp(0.0, 255.0)) as u8;
+        let sparkle = RGBPixel::new(white, white, white);
+        for index in 0..NUM_LEDS {
+          if (xorshift32(&mut self.rng_state) & 0xFF) as u8 >= rate {
+            continue;
           }
-        }
-      }
-      StripSetting::RainbowCycle { cycles } => {
-        let len = self.pixels.len() as f32;
-        for (i, pixel) in self.pixels.iter_mut().enumerate() {
-          // Calculate hue: position along strip * cycles * 360 degrees + animation offset
-          let hue = ((i as f32 / len) * cycles * 360.0 + self.phase * 360.0) % 360.0;
-          let rgb = hsv_to_rgb(hue as u16, 255, 255);
-          let new_r = ((rgb.r as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          let new_g = ((rgb.g as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          let new_b = ((rgb.b as f32 * self.brightness).clamp(0.0, 255.0)) as u8;
-          if pixel.r != new_r || pixel.g != new_g || pixel.b != new_b {
-            changed = true;
-            pixel.r = new_r;
-            pixel.g = new_g;
-            pixel.b = new_b;
+          if self.overlay_restore.push((index, self.pixels[index])).is_err() {
+            break; // MAX_SPARKLES reached
           }
+          self.pixels[index] = sparkle;
         }
+        !self.overlay_restore.is_empty()
       }
-      StripSetting::Custom => {
-        // For the user to custom set pixels directly, do nothing here
-      }
+      None => false,
     }
-    // Advance phase for animations
-    if self.reverse_animation {
-      self.phase = (self.phase - self.phase_step + 1.0) % 1.0;
-    } else {
-      self.phase = (self.phase + self.phase_step) % 1.0;
+  }
+
+  /// Set how long to hold the line low after the last LED, on top of the RMT idle time.
+  /// Some long strips need a longer reset gap than the 50us WS2812 minimum. Clamped to MAX_RESET_US.
+  pub fn set_reset_us(&mut self, reset_us: u16) {
+    self.reset_us = reset_us.min(MAX_RESET_US);
+  }
+
+  /// Set the hue rotation applied to every pixel when generating pulse data
+  pub fn set_hue_shift(&mut self, degrees: u16) {
+    self.hue_shift = degrees % 360;
+  }
+
+  pub fn get_saturation(&self) -> f32 {
+    self.saturation
+  }
+
+  /// Set the saturation multiplier applied to every pixel when generating pulse data.
+  /// 0.0 gives grayscale, 1.0 leaves pixels untouched.
+  pub fn set_saturation(&mut self, saturation: f32) {
+    self.saturation = saturation.max(0.0);
+  }
+
+  pub fn get_value(&self) -> f32 {
+    self.value
+  }
+
+  /// Set the value (HSV) multiplier applied to every pixel when generating pulse data.
+  /// 1.0 leaves pixels untouched.
+  pub fn set_value(&mut self, value: f32) {
+    self.value = value.max(0.0);
+  }
+
+  /// Set a brightness gradient along the strip, multiplied on top of the global brightness.
+  /// e.g. to make the far end brighter to make up for viewing angle. Equal values are uniform.
+  pub fn set_brightness_profile(&mut self, start: f32, end: f32) {
+    self.brightness_start = start.max(0.0);
+    self.brightness_end = end.max(0.0);
+  }
+
+  pub fn get_gamma(&self) -> f32 {
+    self.gamma
+  }
+
+  /// Set the gamma exponent used to correct every channel before it is sent to the strip,
+  /// e.g. 2.2 so brightness steps look even. 1.0 turns correction off.
+  /// Non-positive (or NaN) gamma is ignored.
+  pub fn set_gamma(&mut self, gamma: f32) {
+    if gamma.is_nan() || gamma <= 0.0 || gamma == self.gamma {
+      return;
     }
-    changed
+    self.gamma = gamma;
+    gamma_table(gamma, &mut self.gamma_lut);
   }
 
-  /// Applies a SerialCommand modifying the LED strip settings or individual pixels.
-  pub fn apply_command(&mut self, command: &SerialCommand) {
-    // Mark if raw values have changed
-    match command.action {
-      0x01 | 0x02 | 0x04 => {
-        self.raw_value_changed = true;
-      },
-      _ => {}
+  /// Set how long without a command counts as losing the host, 0 disables the timeout
+  pub fn set_host_timeout(&mut self, timeout_ms: u32) {
+    self.host_timeout_ms = timeout_ms;
+  }
+
+  /// Set the setting to switch to when the host is lost, None to turn the strip off
+  pub fn set_host_fallback(&mut self, fallback: Option<StripSetting>) {
+    self.host_fallback = fallback;
+  }
+
+  /// Switch to the host fallback once `ms_since_last_command` passes the host timeout.
+  /// Only applied once per loss, the next command (of any kind) rearms it.
+  /// Returns true if the fallback was applied by this call.
+  pub fn check_host_timeout(&mut self, ms_since_last_command: u64) -> bool {
+    if self.host_timeout_ms == 0 || ms_since_last_command < self.host_timeout_ms as u64 {
+      self.host_lost = false;
+      return false;
     }
-    // Actually apply the command
-    match command.action {
-      0x01 => { // Set on / off
-        let state = command.data[0];
-        self.is_on = state != 0;
-      },
-      0x02 => { // Set value
-        let value_id = command.data[0];
-        match value_id {
-          0x00 => { // Global brightness
-            let brightness = f32::from_be_bytes([
-              command.data[1],
-              command.data[2],
-              command.data[3],
-              command.data[4],
-            ]);
-            self.set_brightness(brightness);
-          },
-          0x01 => { // Phase step
-            let phase_step = f32::from_be_bytes([
-              command.data[1],
-              command.data[2],
-              command.data[3],
-              command.data[4],
-            ]);
-            self.set_phase_step(phase_step);
-          },
-          0x02 => { // Num LEDs to update
-            let num_leds = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
-            self.num_leds_to_update = num_leds.min(NUM_LEDS);
-          },
-          0x03 => { // Frames per second
-            let fps = command.data[1];
-            self.frames_per_second = fps;
-          },
-          0x04 => { // Reverse animation
-            let reverse = command.data[1] != 0;
-            self.set_reverse_animation(reverse);
-          },
-          _ => {}, // Unknown value ID, ignore
-        }
-      },
-      0x03 => { // Set StripSetting
-        let setting_id = command.data[0];
-        let setting = match setting_id {
-          0x00 => StripSetting::Custom,
-          0x01 => {
-            StripSetting::Breathing {
-              r: command.data[1],
-              g: command.data[2],
-              b: command.data[3],
-            }
-          },
-          0x02 => {
-            StripSetting::SolidColor {
-              r: command.data[1],
-              g: command.data[2],
-              b: command.data[3],
-            }
-          },
-          0x03 => {
-            let cycles = f32::from_be_bytes([
-              command.data[1],
-              command.data[2],
-              command.data[3],
-              command.data[4],
-            ]);
-            StripSetting::RainbowCycle { cycles }
-          },
-          _ => return, // Unknown setting, ignore
-        };
+    if self.host_lost {
+      return false;
+    }
+    self.host_lost = true;
+    match self.host_fallback {
+      Some(setting) => {
+        self.set_on(true);
         self.set_setting(setting);
-      },
-      0x04 => { // Manual color input
-        let start_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
-        let color_data = &command.data[2..(command.length as usize)];
-        let num_leds = color_data.len() / 3;
+      }
+      None => self.set_on(false),
+    }
+    true
+  }
 
-        self.set_setting(StripSetting::Custom);
+  /// Send the next frame even if no pixels changed, to fix LEDs that latched a wrong color
+  /// from noise on the data line. Nothing is sent otherwise until something changes.
+  pub fn request_refresh(&mut self) {
+    self.force_refresh = true;
+  }
 
-        for i in 0..num_leds {
-          let led_index = start_index + i;
-          if led_index >= NUM_LEDS {
-            break; // Don't exceed strip bounds
-          }
-          let offset = i * 3;
-          self.set_pixel(led_index, RGBPixel::new(
-            color_data[offset],
-            color_data[offset + 1],
-            color_data[offset + 2],
-          ));
-        }
-      },
-      _ => {
-        // Unknown command, ignore
+  /// Whether a refresh was requested, clearing the request. render() calls this,
+  /// main loops that transmit on their own should send a frame when it returns true.
+  pub fn take_refresh(&mut self) -> bool {
+    core::mem::take(&mut self.force_refresh)
+  }
+
+  pub fn is_inverted(&self) -> bool {
+    self.inverted
+  }
+
+  /// Set whether to invert the data line, for strips behind an inverting level shifter.
+  /// Takes effect on the next generate_pulse_data(). The RMT channel's idle level
+  /// has to be flipped to match, see idle_level().
+  pub fn set_inverted(&mut self, inverted: bool) {
+    if self.inverted != inverted {
+      self.inverted = inverted;
+      self.raw_value_changed = true; // Every pulse changes, so resend the frame
+    }
+  }
+
+  /// Set the curve Breathing and BreathingRGB follow, e.g. Exponential for a sharper breath
+  pub fn set_breathing_easing(&mut self, easing: Easing) {
+    self.breathing_easing = easing;
+  }
+
+  /// Set whether over-bright colors are scaled down to keep their hue (true)
+  /// or have each channel clipped at 255 (false, default), see scale_rgb()
+  pub fn set_preserve_hue(&mut self, preserve_hue: bool) {
+    self.preserve_hue = preserve_hue;
+  }
+
+  /// Set per channel multipliers (0-255 mapped to 0.0-1.0) for white in SolidColor, e.g. lower blue
+  /// for a strip whose white looks too cold. Only the white part of the color (the amount all three
+  /// channels share) is balanced, so pure and saturated colors stay as they are. White (all 255) by default.
+  pub fn set_white_balance(&mut self, balance: RGBPixel) {
+    self.white_balance = balance;
+  }
+
+  /// Set whether the global brightness is dithered over frames. At low brightness, faint channels
+  /// (e.g. 1 at brightness 0.4) are then on in that fraction of frames instead of always off.
+  /// Animations keep changing every frame while this is on, so every frame is transmitted.
+  pub fn set_dither_brightness(&mut self, dither: bool) {
+    self.set_render_pipeline(RenderPipeline { dither, ..self.pipeline });
+  }
+
+  pub fn get_render_pipeline(&self) -> RenderPipeline {
+    self.pipeline
+  }
+
+  /// Turn post-processing stages on or off, see RenderPipeline for the order they run in.
+  /// Takes effect on the next update_pixels() and generate_pulse_data().
+  pub fn set_render_pipeline(&mut self, pipeline: RenderPipeline) {
+    self.pipeline = pipeline;
+    self.dither = 0.0;
+  }
+
+  pub fn get_color_vision_mode(&self) -> ColorVisionMode {
+    self.color_vision
+  }
+
+  /// Remap colors for a color vision deficiency, on top of any setting. ColorVisionMode::None by default,
+  /// which leaves the output untouched. Takes effect on the next generate_pulse_data().
+  pub fn set_color_vision_mode(&mut self, mode: ColorVisionMode) {
+    self.color_vision = mode;
+  }
+
+  /// Copy `pixels` into the scroll framebuffer starting at `start`, and set its length to `len`.
+  /// Lets a framebuffer too big for one frame be uploaded in chunks. Anything past MAX_SCROLL_PIXELS is dropped.
+  pub fn set_scroll_pixels(&mut self, len: usize, start: usize, pixels: &[RGBPixel]) {
+    self.scroll_len = len.min(MAX_SCROLL_PIXELS);
+    for (i, pixel) in pixels.iter().enumerate() {
+      if let Some(slot) = self.scroll_buffer.get_mut(start + i) {
+        *slot = *pixel;
       }
     }
   }
-}
\ No newline at end of file
+
+  /// Set the lowest non-zero value a channel is sent to the strip as, applied last
+  /// (after gamma). Some strips flicker at very low values, raising them avoids that.
+  /// Channels that are 0 stay off. 0 or 1 disables the floor.
+  pub fn set_channel_floor(&mut self, floor: u8) {
+    self.channel_floor = floor;
+  }
+
+  /// Current animation phase, 0.0 up to (not including) 1.0
+  pub fn get_phase(&self) -> f32 {
+    self.phase
+  }
+
+  /// Set the animation phase directly, clamped to 0.0..=1.0.
+  /// NaN and infinity are ignored, once in the phase they never leave it.
+  pub fn set_phase(&mut self, phase: f32) {
+    if phase.is_finite() {
+      self.phase = phase.clamp(0.0, 1.0) % 1.0;
+    }
+  }
+
+  pub fn is_frozen(&self) -> bool {
+    self.frozen
+  }
+
+  /// Pause the animation on the current frame without changing the setting. While frozen,
+  /// update_pixels() neither advances phase nor renders, so only commands change pixels.
+  /// Unfreezing carries on from the same phase.
+  pub fn set_frozen(&mut self, frozen: bool) {
+    self.frozen = frozen;
+  }
+
+  /// Restart the current animation from the beginning
+  pub fn reset_phase(&mut self) {
+    self.phase = 0.0;
+  }
+
+  /// Step phase forwards (or backwards if reversed) and wrap into 0.0..1.0.
+  /// phase_step may be negative, which also runs the animation backwards.
+  fn advance_phase(&mut self) {
+    let mut step = if self.reverse_animation { -self.phase_step } else { self.phase_step };
+    if self.speed_modulation_depth > 0.0 && matches!(self.setting, StripSetting::RainbowCycle { .. }) {
+      // Separate slow oscillator, so the modulation keeps its own pace whatever the phase step is
+      let t = self.speed_modulation_frame as f32 / self.speed_modulation_period as f32;
+      step *= 1.0 + self.speed_modulation_depth * fast_sin(t);
+      self.speed_modulation_frame = (self.speed_modulation_frame + 1) % self.speed_modulation_period;
+    }
+    self.phase = (self.phase + step).rem_euclid(1.0);
+    // A tiny negative value rounds up to exactly 1.0 in rem_euclid, keep it below 1.0
+    if self.phase >= 1.0 {
+      self.phase = 0.0;
+    }
+  }
+
+  /// Turn the strip on or off. Turning off starts the fade for OffMode::Fade,
+  /// turning on starts the soft start ramp.
+  pub fn set_on(&mut self, on: bool) {
+    if self.is_on && !on && let OffMode::Fade { frames } = self.off_mode {
+      self.fade_frames_left = frames;
+    }
+    if !self.is_on && on {
+      self.soft_start_left = self.soft_start_frames;
+    }
+    self.is_on = on;
+  }
+
+  /// Ramp the output up from off over `frames` frames after turning on (and at boot with a saved config),
+  /// so a strip turning on at full white doesn't draw all its current at once. 0 (default) turns on instantly.
+  /// Scales the output of any setting, like the mask.
+  pub fn set_soft_start(&mut self, frames: u16) {
+    self.soft_start_frames = frames;
+    self.soft_start_left = self.soft_start_left.min(frames);
+  }
+
+  pub fn set_off_mode(&mut self, mode: OffMode) {
+    self.off_mode = mode;
+  }
+
+  /// Render one frame of the strip being off, according to off_mode.
+  /// Returns true if any pixel values were changed.
+  fn render_off(&mut self) -> bool {
+    match self.off_mode {
+      OffMode::Clear => self.clear(),
+      OffMode::Fade { .. } => {
+        if self.fade_frames_left == 0 {
+          return self.clear();
+        }
+        // Scaling the distance to the idle color by (n-1)/n every frame
+        // fades linearly from the starting colors to the idle color
+        let left = self.fade_frames_left as i16;
+        let idle = self.idle_color;
+        let scale = |c: u8, target: u8| (target as i16 + (c as i16 - target as i16) * (left - 1) / left) as u8;
+        let mut changed = false;
+        for pixel in self.pixels.iter_mut() {
+          let new_pixel = RGBPixel::new(scale(pixel.r, idle.r), scale(pixel.g, idle.g), scale(pixel.b, idle.b));
+          changed |= pixel.set_if_changed(new_pixel);
+        }
+        self.fade_frames_left -= 1;
+        changed
+      }
+      OffMode::KeepLastFrame => false,
+    }
+  }
+
+  /// Set every pixel to the idle color, used while the strip is off.
+  /// Returns true if any pixel values were changed.
+  fn clear(&mut self) -> bool {
+    self.fill(self.idle_color)
+  }
+
+  /// Set every pixel to off (black), regardless of the idle color.
+  /// Returns true if any pixel values were changed.
+  pub fn blackout(&mut self) -> bool {
+    self.fill(RGBPixel::off())
+  }
+
+  fn fill(&mut self, color: RGBPixel) -> bool {
+    let mut changed = false;
+    for pixel in self.pixels.iter_mut() {
+      changed |= pixel.set_if_changed(color);
+    }
+    changed
+  }
+
+  /// Set the color shown while the strip is off (default: off)
+  pub fn set_idle_color(&mut self, color: RGBPixel) {
+    self.idle_color = color;
+  }
+
+  pub fn get_num_leds_to_update(&self) -> usize {
+    self.num_leds_to_update
+  }
+
+  pub fn get_update_start(&self) -> usize {
+    self.update_start
+  }
+
+  /// Only send pixels `start..start + num` to the strip, clamped to the end of the strip.
+  /// WS2812s are daisy chained, so the first pixel sent always lands on the first LED.
+  /// Animations are spread over the window, so a rainbow cycle spans the `num` LEDs being sent.
+  pub fn set_update_window(&mut self, start: usize, num: usize) {
+    self.update_start = start.min(NUM_LEDS);
+    self.num_leds_to_update = num.min(NUM_LEDS - self.update_start);
+  }
+
+  /// Set the color format of LEDs from `start` until the next segment, for mixing GRB and GRBW LEDs
+  /// on one data line. Returns false if there are already MAX_SEGMENTS segments,
+  /// or for GRBW without the rgbw feature since pulse_data only has room for GRB then.
+  /// Takes effect on the next generate_pulse_data().
+  pub fn set_segment_format(&mut self, start: usize, format: ColorFormat) -> bool {
+    if format == ColorFormat::Grbw && !cfg!(feature = "rgbw") {
+      return false;
+    }
+    let start = start.min(NUM_LEDS - 1);
+    match self.segments.iter().position(|&(s, _)| s >= start) {
+      Some(i) if self.segments[i].0 == start => {
+        self.segments[i].1 = format;
+        true
+      }
+      Some(i) => self.segments.insert(i, (start, format)).is_ok(),
+      None => self.segments.push((start, format)).is_ok(),
+    }
+  }
+
+  pub fn get_chip_timing(&self) -> ChipTiming {
+    self.chip_timing
+  }
+
+  /// Set the bit timing for the LED chips on the strip, WS2812B by default.
+  /// Takes effect on the next generate_pulse_data().
+  pub fn set_chip_timing(&mut self, timing: ChipTiming) {
+    self.chip_timing = timing;
+  }
+
+  /// Remove all segments, back to GRB for the whole strip
+  pub fn clear_segments(&mut self) {
+    self.segments.clear();
+  }
+
+  /// Index in pulse_data of the first pulse for LED `index` (or the end of the LED pulses for NUM_LEDS)
+  fn pulse_offset(&self, index: usize) -> usize {
+    let mut offset = 0;
+    let mut position = 0;
+    let mut format = ColorFormat::Grb;
+    for &(start, next_format) in self.segments.iter() {
+      if start >= index {
+        break;
+      }
+      offset += (start - position) * format.pulses_per_led();
+      position = start;
+      format = next_format;
+    }
+    offset + (index - position) * format.pulses_per_led()
+  }
+
+  // Return a slice from the same one as the input buffer because if the buffer is bigger than necessary,
+  // only the first part should be sent.
+  // The last PulseCode needs to be the end marker.
+
+  /// Wrapper to get pulse data based on num_leds_to_update.
+  /// Panics if the buffer is too small, see try_get_pulse_data().
+  pub fn get_pulse_data<'a>(&self, buffer: &'a mut [Pulse]) -> &'a [Pulse] {
+    match self.try_get_pulse_data(buffer) {
+      Ok(pulse_data) => pulse_data,
+      Err(PulseError::BufferTooSmall { .. }) => panic!("Buffer too small for pulse data"),
+    }
+  }
+
+  /// Get pulse data based on update_start and num_leds_to_update,
+  /// returning an error instead of panicking if the buffer is too small.
+  /// A buffer of PULSE_BUFFER_LEN is always big enough.
+  pub fn try_get_pulse_data<'a>(&self, buffer: &'a mut [Pulse]) -> Result<&'a [Pulse], PulseError> {
+    self.try_get_pulse_data_limited(self.update_start, self.num_leds_to_update, buffer)
+  }
+
+  /// Copy pulse data for `num` LEDs starting at `start` into the provided buffer.
+  /// Adds the reset pulses and end marker after the specified number of LEDs.
+  #[cfg(not(feature = "packed-pulses"))]
+  fn try_get_pulse_data_limited<'a>(&self, start: usize, num: usize, buffer: &'a mut [Pulse]) -> Result<&'a [Pulse], PulseError> {
+    // Only the active LEDs are sent
+    let start = start.min(self.active_len);
+    let len = num.min(self.active_len - start);
+    // Reset pulses + end marker, stored after the pulses for the last LED
+    let tail = &self.pulse_data[self.led_pulse_count..self.led_pulse_count + self.reset_pulse_count + 1];
+    let leds = &self.pulse_data[self.pulse_offset(start)..self.pulse_offset(start + len)];
+    let required_len = leds.len() + tail.len();
+    if buffer.len() < required_len {
+      return Err(PulseError::BufferTooSmall { required: required_len, provided: buffer.len() });
+    }
+    buffer[..leds.len()].copy_from_slice(leds);
+    buffer[leds.len()..required_len].copy_from_slice(tail);
+    Ok(&buffer[..required_len])
+  }
+
+  /// Expand the packed bytes for `num` LEDs starting at `start` into pulses in the provided buffer.
+  /// Adds the reset pulses and end marker after the specified number of LEDs.
+  #[cfg(feature = "packed-pulses")]
+  fn try_get_pulse_data_limited<'a>(&self, start: usize, num: usize, buffer: &'a mut [Pulse]) -> Result<&'a [Pulse], PulseError> {
+    // Only the active LEDs are sent
+    let start = start.min(self.active_len);
+    let len = num.min(self.active_len - start);
+    let bytes = &self.pulse_data[self.pulse_offset(start) / 8..self.pulse_offset(start + len) / 8];
+    let leds_len = bytes.len() * 8;
+    let required_len = leds_len + self.reset_pulse_count + 1;
+    if buffer.len() < required_len {
+      return Err(PulseError::BufferTooSmall { required: required_len, provided: buffer.len() });
+    }
+    bytes_to_pulses(bytes, self.chip_timing, self.inverted, &mut buffer[..leds_len]);
+    reset_pulses(self.reset_us, self.inverted, &mut buffer[leds_len..required_len - 1]);
+    buffer[required_len - 1] = Pulse::end_marker();
+    Ok(&buffer[..required_len])
+  }
+
+  /// Expand chunk `index` of the frame get_pulse_data() would return into `buffer`, PACKED_CHUNK_LEN is always
+  /// big enough. Chunks are PACKED_CHUNK_BYTES of the LEDs' bytes each (the last one shorter), then one
+  /// with the reset pulses, each ending in an end marker. Returns the chunk's length, None past the last one.
+  #[cfg(feature = "packed-pulses")]
+  pub fn packed_chunk(&self, index: usize, buffer: &mut [Pulse]) -> Option<usize> {
+    let start = self.update_start.min(self.active_len);
+    let len = self.num_leds_to_update.min(self.active_len - start);
+    let bytes = &self.pulse_data[self.pulse_offset(start) / 8..self.pulse_offset(start + len) / 8];
+    let chunks = bytes.len().div_ceil(PACKED_CHUNK_BYTES);
+    let pulses_len = if index < chunks {
+      let chunk = &bytes[index * PACKED_CHUNK_BYTES..bytes.len().min((index + 1) * PACKED_CHUNK_BYTES)];
+      bytes_to_pulses(chunk, self.chip_timing, self.inverted, &mut buffer[..chunk.len() * 8]);
+      chunk.len() * 8
+    } else if index == chunks {
+      reset_pulses(self.reset_us, self.inverted, &mut buffer[..self.reset_pulse_count])
+    } else {
+      return None;
+    };
+    buffer[pulses_len] = Pulse::end_marker();
+    Some(pulses_len + 1)
+  }
+
+  /// Encode the LEDs get_pulse_data() would send as SPI data (see byte_to_spi()), then low bytes for the reset.
+  /// For sending over SPI with DMA instead of the RMT, see transmit_spi(). SPI_BUFFER_LEN is always big enough.
+  /// The SPI line can't idle high, so `inverted` is ignored here.
+  #[cfg(feature = "packed-pulses")]
+  pub fn try_get_spi_data<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a [u8], PulseError> {
+    let start = self.update_start.min(self.active_len);
+    let len = self.num_leds_to_update.min(self.active_len - start);
+    let bytes = &self.pulse_data[self.pulse_offset(start) / 8..self.pulse_offset(start + len) / 8];
+    let leds_len = bytes.len() * SPI_BYTES_PER_BYTE;
+    let required_len = leds_len + spi_reset_bytes(self.reset_us);
+    if buffer.len() < required_len {
+      return Err(PulseError::BufferTooSmall { required: required_len, provided: buffer.len() });
+    }
+    bytes_to_spi(bytes, &mut buffer[..leds_len]);
+    buffer[leds_len..required_len].fill(0);
+    Ok(&buffer[..required_len])
+  }
+
+  /// Write pulse data for all LEDs into the internal buffer.
+  /// Post-processing (hue shift, saturation, value etc.) is applied here, so it works on top of any setting.
+  /// Stages run in RenderPipeline order, those turned off in the pipeline are skipped.
+  /// The color vision remap runs right after HSV adjust when a ColorVisionMode is set.
+  pub fn generate_pulse_data(&mut self) {
+    let post_process = self.pipeline.hsv_adjust && (self.hue_shift != 0 || self.saturation != 1.0 || self.value != 1.0);
+    let uniform_profile = !self.pipeline.brightness_profile || (self.brightness_start == 1.0 && self.brightness_end == 1.0);
+    let gamma_correct = self.pipeline.gamma && self.gamma != 1.0;
+    let raise_floor = self.pipeline.channel_floor && self.channel_floor > 1;
+    // How far into the soft start ramp we are, 255 once it's done
+    let soft_start = match self.soft_start_left {
+      0 => 255,
+      left => ((self.soft_start_frames - left) as u32 * 255 / self.soft_start_frames as u32) as u16,
+    };
+    // Dim channels are raised to the floor, channels that are off stay off
+    let floor = |x: u8| if x == 0 { 0 } else { x.max(self.channel_floor) };
+    // Pulses per LED differ between segments, so the offset is counted up as we go
+    let mut offset = 0;
+    let mut segments = self.segments.iter().peekable();
+    let mut format = ColorFormat::Grb;
+    for (i, pixel) in self.pixels[..self.active_len].iter().enumerate() {
+      while let Some(&(_, next_format)) = segments.next_if(|&&(start, _)| start <= i) {
+        format = next_format;
+      }
+      let mut pixel = *pixel;
+      if post_process {
+        let (h, s, v) = rgb_to_hsv(&pixel);
+        let s = ((s as f32 * self.saturation).clamp(0.0, 255.0)) as u8;
+        let v = ((v as f32 * self.value).clamp(0.0, 255.0)) as u8;
+        pixel = hsv_to_rgb(h + self.hue_shift, s, v);
+      }
+      if self.color_vision != ColorVisionMode::None {
+        pixel = color_vision_remap(&pixel, self.color_vision);
+      }
+      if !uniform_profile {
+        let t = i as f32 / (self.active_len - 1).max(1) as f32;
+        let factor = self.brightness_start + (self.brightness_end - self.brightness_start) * t;
+        let (r, g, b) = scale_rgb(pixel.r, pixel.g, pixel.b, factor, self.preserve_hue, 0.0);
+        pixel = RGBPixel::new(r, g, b);
+      }
+      if self.mask_active {
+        let m = self.mask[i] as u16;
+        let apply = |c: u8| (c as u16 * m / 255) as u8;
+        pixel = RGBPixel::new(apply(pixel.r), apply(pixel.g), apply(pixel.b));
+      }
+      if soft_start < 255 {
+        let apply = |c: u8| (c as u16 * soft_start / 255) as u8;
+        pixel = RGBPixel::new(apply(pixel.r), apply(pixel.g), apply(pixel.b));
+      }
+      if gamma_correct {
+        pixel = RGBPixel::new(
+          self.gamma_lut[pixel.r as usize],
+          self.gamma_lut[pixel.g as usize],
+          self.gamma_lut[pixel.b as usize],
+        );
+      }
+      if raise_floor {
+        pixel = RGBPixel::new(floor(pixel.r), floor(pixel.g), floor(pixel.b));
+      }
+      #[cfg(not(feature = "packed-pulses"))]
+      {
+        let pulses = &mut self.pulse_data[offset..offset + format.pulses_per_led()];
+        match format {
+          ColorFormat::Grb => rgb_to_pulses(&pixel, self.chip_timing, self.inverted, pulses),
+          ColorFormat::Grbw => rgbw_to_pulses(&pixel, self.chip_timing, self.inverted, pulses),
+        }
+      }
+      #[cfg(feature = "packed-pulses")]
+      {
+        let len = format.pulses_per_led() / 8;
+        self.pulse_data[offset / 8..offset / 8 + len].copy_from_slice(&color_bytes(&pixel, format)[..len]);
+      }
+      offset += format.pulses_per_led();
+    }
+    #[cfg(not(feature = "packed-pulses"))]
+    {
+      self.led_pulse_count = offset;
+      let tail = &mut self.pulse_data[offset..];
+      self.reset_pulse_count = reset_pulses(self.reset_us, self.inverted, &mut tail[..MAX_RESET_PULSES]);
+      tail[self.reset_pulse_count] = Pulse::end_marker();
+    }
+    #[cfg(feature = "packed-pulses")]
+    {
+      // Only the count is kept, the reset pulses are written again in try_get_pulse_data()
+      let mut tail = [Pulse::default(); MAX_RESET_PULSES];
+      self.reset_pulse_count = reset_pulses(self.reset_us, self.inverted, &mut tail);
+    }
+  }
+
+  /// Compute new pixel values based on the current setting and update internal pixel buffer.
+  /// Returns true if any pixel values were changed.
+  pub fn update_pixels(&mut self) -> bool {
+    let mut changed = self.raw_value_changed;
+    self.raw_value_changed = false;
+    self.frame_count = self.frame_count.wrapping_add(1);
+    // The ramp is applied in generate_pulse_data(), every step of it has to be sent
+    if self.soft_start_left > 0 && self.is_on {
+      self.soft_start_left -= 1;
+      changed = true;
+    }
+    // Keep showing the current frame, overlay included. Turning off still works while frozen
+    if self.frozen && self.is_on {
+      return changed;
+    }
+    changed |= self.restore_overlay();
+    if self.pipeline.dither {
+      // Bit reversed frame count: spreads evenly over 0.0 to 1.0 within every 2, 4, 8... frames
+      self.dither = (self.frame_count as u8).reverse_bits() as f32 / 256.0;
+    }
+
+    if !self.is_on {
+      // Once nothing changes, nothing more is transmitted until turned back on
+      changed |= self.render_off();
+      return changed;
+    }
+    // LEDs past active_len are off and left alone
+    let active_len = self.active_len;
+    // Animations are spread over the LEDs being sent, starting at update_start
+    let window_start = self.update_start.min(active_len - 1);
+    let window_len = self.num_leds_to_update.min(active_len - window_start).max(1);
+    // Position of LED `i` in the window, the pattern repeats outside it
+    let window_index = |i: usize| (i + window_len - window_start % window_len) % window_len;
+    match self.setting {
+      StripSetting::Breathing { r, g, b, min, max } => {
+        // Calculate brightness factor along the breathing curve (sine by default), mapped into min..max
+        let level = min as f32 + (max as f32 - min as f32) * breath(self.breathing_easing, self.phase);
+        let brightness_factor = level / 255.0 * self.brightness_factor;
+        let (new_r, new_g, new_b) = scale_rgb(r, g, b, brightness_factor, self.preserve_hue, self.dither);
+        for pixel in self.pixels[..active_len].iter_mut() {
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::BreathingRGB { r, g, b, offset_r, offset_g, offset_b } => {
+        // Same curve as Breathing, evaluated separately per channel, then the brightness on top
+        let level = |c: u8, offset: u8| {
+          let phase = self.phase + offset as f32 / 256.0;
+          (c as f32 * breath(self.breathing_easing, phase)) as u8
+        };
+        let (new_r, new_g, new_b) = scale_rgb(
+          level(r, offset_r),
+          level(g, offset_g),
+          level(b, offset_b),
+          self.brightness_factor,
+          self.preserve_hue,
+          self.dither,
+        );
+        for pixel in self.pixels[..active_len].iter_mut() {
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::SolidColor { r, g, b } => {
+        // Balance only the white part, the rest of each channel is color and stays as it is
+        let white = r.min(g).min(b);
+        let balance = |c: u8, m: u8| c - white + (white as u16 * m as u16 / 255) as u8;
+        let wb = self.white_balance;
+        let (r, g, b) = (balance(r, wb.r), balance(g, wb.g), balance(b, wb.b));
+        for pixel in self.pixels[..active_len].iter_mut() {
+          let (new_r, new_g, new_b) = scale_rgb(r, g, b, self.brightness_factor, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::RainbowCycle { cycles, sat, val } => {
+        let len = window_len;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let hue = rainbow_hue(i, len, cycles, self.phase);
+          let rgb = hsv_to_rgb(hue, sat, val);
+          let (new_r, new_g, new_b) = scale_rgb(rgb.r, rgb.g, rgb.b, self.brightness_factor, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::RainbowSweep { cycles } => {
+        let len = window_len;
+        let revealed = ((self.setting_frame as f32 * self.phase_step.abs() * len as f32) as usize).min(len);
+        self.setting_frame = self.setting_frame.saturating_add(1);
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let (new_r, new_g, new_b) = if i < revealed {
+            // Phase 0, the rainbow doesn't move once revealed
+            let rgb = hsv_to_rgb(rainbow_hue(i, len, cycles, 0.0), 255, 255);
+            scale_rgb(rgb.r, rgb.g, rgb.b, self.brightness_factor, self.preserve_hue, self.dither)
+          } else {
+            (0, 0, 0)
+          };
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::RainbowChase { cycles, spacing } => {
+        let len = window_len;
+        // Treat a spacing of 0 as 1 (every LED lit)
+        let spacing = spacing.max(1) as usize;
+        // Which LED in each group of `spacing` is lit, advances with phase
+        let offset = ((self.get_chase_phase() * spacing as f32) as usize + self.chase_start as usize) % spacing;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let (new_r, new_g, new_b) = if i % spacing == offset {
+            let rgb = hsv_to_rgb(rainbow_hue(i, len, cycles, self.phase), 255, 255);
+            scale_rgb(rgb.r, rgb.g, rgb.b, self.brightness_factor, self.preserve_hue, self.dither)
+          } else {
+            (0, 0, 0)
+          };
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Ripple { r, g, b, origin } => {
+        // Width of the ring, in LEDs
+        const RING_WIDTH: f32 = 8.0;
+        let len = window_len;
+        let origin = origin as usize * (len - 1) / 255;
+        // Distance to the furthest end, so the ring always leaves the strip before restarting
+        let max_distance = origin.max(len - 1 - origin) as f32 + RING_WIDTH;
+        let radius = self.phase * max_distance;
+        // Fade out as the ring expands
+        let fade = (1.0 - self.phase) * self.brightness_factor;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let distance = (i as f32 - origin as f32).abs();
+          let intensity = (1.0 - (distance - radius).abs() / RING_WIDTH).max(0.0) * fade;
+          let (new_r, new_g, new_b) = scale_rgb(r, g, b, intensity, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Marquee { r1, g1, b1, r2, g2, b2, block } => {
+        // Treat a block of 0 as 1 to avoid dividing by zero
+        let block = block.max(1) as usize;
+        let period = block * 2;
+        let offset = ((self.get_chase_phase() * period as f32) as usize + self.chase_start as usize) % period;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let (r, g, b) = if (i + period - offset) % period < block {
+            (r1, g1, b1)
+          } else {
+            (r2, g2, b2)
+          };
+          let (new_r, new_g, new_b) = scale_rgb(r, g, b, self.brightness_factor, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::SelfTest => {
+        let color = match self.setting_frame / SELF_TEST_FRAMES_PER_COLOR {
+          0 => RGBPixel::red(),
+          1 => RGBPixel::green(),
+          2 => RGBPixel::blue(),
+          3 => RGBPixel::white(),
+          _ => {
+            // Done, settle with everything off
+            self.set_setting(StripSetting::Custom);
+            RGBPixel::off()
+          }
+        };
+        self.setting_frame = self.setting_frame.saturating_add(1);
+        let (new_r, new_g, new_b) = scale_rgb(color.r, color.g, color.b, self.brightness_factor, self.preserve_hue, self.dither);
+        for pixel in self.pixels[..active_len].iter_mut() {
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::FadeTo { r, g, b, rate } => {
+        let (target_r, target_g, target_b) = scale_rgb(r, g, b, self.brightness_factor, self.preserve_hue, self.dither);
+        // Treat a rate of 0 as 1 so the fade always finishes
+        let rate = rate.max(1);
+        let step = |current: u8, target: u8| {
+          if current < target {
+            current.saturating_add(rate).min(target)
+          } else {
+            current.saturating_sub(rate).max(target)
+          }
+        };
+        for pixel in self.pixels[..active_len].iter_mut() {
+          let new_r = step(pixel.r, target_r);
+          let new_g = step(pixel.g, target_g);
+          let new_b = step(pixel.b, target_b);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Scroll { speed } => {
+        let len = self.scroll_len;
+        // Position of the first LED in the framebuffer, fractional for interpolation
+        let start = (self.phase * speed * len as f32).rem_euclid(len.max(1) as f32);
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let (new_r, new_g, new_b) = if len == 0 {
+            (0, 0, 0) // Nothing uploaded yet
+          } else {
+            let position = start + i as f32;
+            let index = position as usize;
+            let frac = position - index as f32;
+            let a = self.scroll_buffer[index % len];
+            let b = self.scroll_buffer[(index + 1) % len];
+            let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * frac + 0.5).clamp(0.0, 255.0) as u8;
+            scale_rgb(lerp(a.r, b.r), lerp(a.g, b.g), lerp(a.b, b.b), self.brightness_factor, self.preserve_hue, self.dither)
+          };
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Custom => {
+        // For the user to custom set pixels directly, do nothing here
+        // unless they were set as HSV, which is converted to RGB here
+        if self.hsv_mode {
+          for (pixel, (h, s, v)) in self.pixels[..active_len].iter_mut().zip(self.hsv_pixels.iter()) {
+            let new_pixel = hsv_to_rgb(*h, *s, *v);
+            changed |= pixel.set_if_changed(new_pixel);
+          }
+        }
+      }
+      StripSetting::RainbowComet { cycles, tail } => {
+        let len = window_len;
+        let head = ((self.phase * len as f32) as usize).min(len - 1);
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          // LEDs behind the head, wrapping around past index 0
+          let distance = (head + len - i) % len;
+          let (new_r, new_g, new_b) = if distance <= tail as usize {
+            let intensity = (1.0 - distance as f32 / (tail as f32 + 1.0)) * self.brightness_factor;
+            let rgb = hsv_to_rgb(rainbow_hue(i, len, cycles, 0.0), 255, 255);
+            scale_rgb(rgb.r, rgb.g, rgb.b, intensity, self.preserve_hue, self.dither)
+          } else {
+            (0, 0, 0)
+          };
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Wave { r, g, b, count } => {
+        let len = window_len;
+        let count = count.clamp(1, MAX_WAVES) as usize;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let position = i as f32 / len as f32;
+          let sum: f32 = (0..count)
+            .map(|k| fast_sin(position * WAVE_CYCLES[k] - self.phase * WAVE_SPEEDS[k] * WAVE_CYCLES[k]))
+            .sum();
+          let intensity = (0.5 + 0.5 * sum).clamp(0.0, 1.0) * self.brightness_factor;
+          let (new_r, new_g, new_b) = scale_rgb(r, g, b, intensity, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::HueGradient { start_hue, end_hue, sat, val } => {
+        let len = window_len;
+        // Signed difference the short way around, -180 to 179 degrees
+        let span = ((end_hue % 360) as i32 - (start_hue % 360) as i32 + 180).rem_euclid(360) - 180;
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let t = i as f32 / (len - 1).max(1) as f32;
+          // round() rather than adding 0.5, which would round negative spans the wrong way
+          let hue = (start_hue as i32 + (span as f32 * t).round() as i32).rem_euclid(360) as u16;
+          let rgb = hsv_to_rgb(hue, sat, val);
+          let (new_r, new_g, new_b) = scale_rgb(rgb.r, rgb.g, rgb.b, self.brightness_factor, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Life { r, g, b, rule, frames } => {
+        // Treat 0 frames as 1 so it always moves
+        self.setting_frame += 1;
+        if self.setting_frame >= frames.max(1) as u16 {
+          self.setting_frame = 0;
+          life_step(&mut self.cells[..active_len], rule);
+          if !self.cells[..active_len].contains(&true) {
+            self.seed_life();
+          }
+        }
+        let alive = scale_rgb(r, g, b, self.brightness_factor, self.preserve_hue, self.dither);
+        for (pixel, &cell) in self.pixels[..active_len].iter_mut().zip(self.cells.iter()) {
+          let (new_r, new_g, new_b) = if cell { alive } else { (0, 0, 0) };
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Gradient { stops, count } => {
+        let len = window_len;
+        let mut palette = [(0.0, RGBPixel::off()); MAX_GRADIENT_STOPS];
+        for (slot, &(position, color)) in palette.iter_mut().zip(stops.iter()) {
+          *slot = (position as f32 / 255.0, color);
+        }
+        let palette = &palette[..(count as usize).min(MAX_GRADIENT_STOPS)];
+        for (i, pixel) in self.pixels[..active_len].iter_mut().enumerate() {
+          let i = window_index(i);
+          let t = i as f32 / (len - 1).max(1) as f32;
+          let rgb = palette_lookup(palette, t);
+          let (new_r, new_g, new_b) = scale_rgb(rgb.r, rgb.g, rgb.b, self.brightness_factor, self.preserve_hue, self.dither);
+          changed |= pixel.set_if_changed(RGBPixel::new(new_r, new_g, new_b));
+        }
+      }
+      StripSetting::Off => {
+        for pixel in self.pixels[..active_len].iter_mut() {
+          changed |= pixel.set_if_changed(RGBPixel::off());
+        }
+      }
+    }
+    // Overlay goes on top of the setting, after brightness has been applied
+    changed |= self.apply_overlay();
+    // Advance phase for animations
+    self.advance_phase();
+    changed
+  }
+
+  /// Parse a StripSetting from a payload starting with the setting ID,
+  /// as sent with Set StripSetting. Returns None for unknown setting IDs.
+  fn parse_setting(command: CommandView<'_>) -> Option<StripSetting> {
+    let setting_id = command.data[0];
+    let setting = match setting_id {
+      0x00 => StripSetting::Off,
+      0x01 => {
+        // Min and max are optional, the full range by default
+        let (min, max) = if command.length >= 6 {
+          (command.data[4], command.data[5])
+        } else {
+          (0, 255)
+        };
+        StripSetting::Breathing {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          min,
+          max,
+        }
+      },
+      0x02 => {
+        StripSetting::SolidColor {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+        }
+      },
+      0x03 => {
+        let cycles = f32::from_be_bytes([
+          command.data[1],
+          command.data[2],
+          command.data[3],
+          command.data[4],
+        ]);
+        // Saturation and value are optional, full by default
+        let (sat, val) = if command.length >= 7 {
+          (command.data[5], command.data[6])
+        } else {
+          (255, 255)
+        };
+        StripSetting::RainbowCycle { cycles, sat, val }
+      },
+      0x04 => {
+        let cycles = f32::from_be_bytes([
+          command.data[1],
+          command.data[2],
+          command.data[3],
+          command.data[4],
+        ]);
+        StripSetting::RainbowChase { cycles, spacing: command.data[5] }
+      },
+      0x05 => {
+        StripSetting::Ripple {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          origin: command.data[4],
+        }
+      },
+      0x06 => {
+        StripSetting::Marquee {
+          r1: command.data[1],
+          g1: command.data[2],
+          b1: command.data[3],
+          r2: command.data[4],
+          g2: command.data[5],
+          b2: command.data[6],
+          block: command.data[7],
+        }
+      },
+      0x07 => {
+        StripSetting::BreathingRGB {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          offset_r: command.data[4],
+          offset_g: command.data[5],
+          offset_b: command.data[6],
+        }
+      },
+      0x08 => StripSetting::SelfTest,
+      0x09 => {
+        StripSetting::FadeTo {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          rate: command.data[4],
+        }
+      },
+      0x0A => {
+        let cycles = f32::from_be_bytes([
+          command.data[1],
+          command.data[2],
+          command.data[3],
+          command.data[4],
+        ]);
+        StripSetting::RainbowSweep { cycles }
+      },
+      0x0B => { // Color temperature, shown as a solid color
+        let kelvin = u16::from_be_bytes([command.data[1], command.data[2]]);
+        let pixel = kelvin_to_rgb(kelvin);
+        StripSetting::SolidColor { r: pixel.r, g: pixel.g, b: pixel.b }
+      },
+      0x0C => {
+        let speed = f32::from_be_bytes([
+          command.data[1],
+          command.data[2],
+          command.data[3],
+          command.data[4],
+        ]);
+        StripSetting::Scroll { speed }
+      },
+      0x0D => StripSetting::Custom,
+      0x0E => {
+        let cycles = f32::from_be_bytes([
+          command.data[1],
+          command.data[2],
+          command.data[3],
+          command.data[4],
+        ]);
+        StripSetting::RainbowComet { cycles, tail: command.data[5] }
+      },
+      0x0F => {
+        StripSetting::Wave {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          count: command.data[4],
+        }
+      },
+      0x10 => {
+        StripSetting::HueGradient {
+          start_hue: u16::from_be_bytes([command.data[1], command.data[2]]),
+          end_hue: u16::from_be_bytes([command.data[3], command.data[4]]),
+          sat: command.data[5],
+          val: command.data[6],
+        }
+      },
+      0x11 => {
+        StripSetting::Life {
+          r: command.data[1],
+          g: command.data[2],
+          b: command.data[3],
+          rule: command.data[4],
+          frames: command.data[5],
+        }
+      },
+      0x12 => {
+        // Count, then position + RGB per stop. Validated to be 1 to MAX_GRADIENT_STOPS stops in order
+        let count = command.data[1];
+        let mut stops = [(0, RGBPixel::off()); MAX_GRADIENT_STOPS];
+        for (stop, bytes) in stops.iter_mut().zip(command.data[2..].chunks_exact(4)).take(count as usize) {
+          *stop = (bytes[0], RGBPixel::new(bytes[1], bytes[2], bytes[3]));
+        }
+        StripSetting::Gradient { stops, count }
+      },
+      _ => return None, // Unknown setting
+    };
+    Some(setting)
+  }
+
+  /// Write the current configuration (brightness, fps, post-processing, on/off state etc.)
+  /// into a blob for saving to flash, protected by the same CRC-16-CCITT as serial frames.
+  /// The StripSetting and pixels are not included.
+  ///
+  /// Layout (big endian): version, brightness, phase step, animation speed (NaN if unset),
+  /// num LEDs to update, fps, flags (on, reverse, wrap, inverted, perceptual brightness), off mode, fade frames, idle color,
+  /// hue shift, saturation, value, brightness profile start, end, gamma, reset time,
+  /// update window start, soft start frames, CRC.
+  pub fn config_snapshot(&self) -> [u8; CONFIG_SNAPSHOT_LEN] {
+    let mut blob = [0u8; CONFIG_SNAPSHOT_LEN];
+    blob[0] = CONFIG_SNAPSHOT_VERSION;
+    blob[1..5].copy_from_slice(&self.brightness.to_be_bytes());
+    blob[5..9].copy_from_slice(&self.phase_step.to_be_bytes());
+    blob[9..13].copy_from_slice(&self.animation_hz.unwrap_or(f32::NAN).to_be_bytes());
+    blob[13..15].copy_from_slice(&(self.num_leds_to_update as u16).to_be_bytes());
+    blob[15] = self.frames_per_second;
+    blob[16] = self.is_on as u8
+      | (self.reverse_animation as u8) << 1
+      | (self.wrap_manual_input as u8) << 2
+      | (self.inverted as u8) << 3
+      | (self.perceptual_brightness as u8) << 4;
+    (blob[17], blob[18]) = match self.off_mode {
+      OffMode::Clear => (0x00, 0),
+      OffMode::Fade { frames } => (0x01, frames),
+      OffMode::KeepLastFrame => (0x02, 0),
+    };
+    blob[19..22].copy_from_slice(&[self.idle_color.r, self.idle_color.g, self.idle_color.b]);
+    blob[22..24].copy_from_slice(&self.hue_shift.to_be_bytes());
+    blob[24..28].copy_from_slice(&self.saturation.to_be_bytes());
+    blob[28..32].copy_from_slice(&self.value.to_be_bytes());
+    blob[32..36].copy_from_slice(&self.brightness_start.to_be_bytes());
+    blob[36..40].copy_from_slice(&self.brightness_end.to_be_bytes());
+    blob[40..44].copy_from_slice(&self.gamma.to_be_bytes());
+    blob[44..46].copy_from_slice(&self.reset_us.to_be_bytes());
+    blob[46..48].copy_from_slice(&(self.update_start as u16).to_be_bytes());
+    blob[48..50].copy_from_slice(&self.soft_start_frames.to_be_bytes());
+    let crc = SerialCommand::checksum_of(blob[0], &blob[1..50]);
+    blob[50..52].copy_from_slice(&crc.to_be_bytes());
+    blob
+  }
+
+  /// Load a configuration written by config_snapshot().
+  /// Returns false and leaves everything untouched if the blob is the wrong size,
+  /// from a different layout version, or fails the CRC (e.g. erased or corrupt flash).
+  pub fn config_restore(&mut self, blob: &[u8]) -> bool {
+    if blob.len() != CONFIG_SNAPSHOT_LEN || blob[0] != CONFIG_SNAPSHOT_VERSION {
+      return false;
+    }
+    let crc = u16::from_be_bytes([blob[50], blob[51]]);
+    if crc != SerialCommand::checksum_of(blob[0], &blob[1..50]) {
+      return false;
+    }
+    let f32_at = |i: usize| f32::from_be_bytes([blob[i], blob[i + 1], blob[i + 2], blob[i + 3]]);
+
+    self.set_perceptual_brightness(blob[16] & 0b10000 != 0);
+    self.set_brightness(f32_at(1));
+    self.set_frames_per_second(blob[15]);
+    self.set_phase_step(f32_at(5));
+    let hz = f32_at(9);
+    if !hz.is_nan() {
+      self.set_animation_hz(hz);
+    }
+    self.set_update_window(
+      u16::from_be_bytes([blob[46], blob[47]]) as usize,
+      u16::from_be_bytes([blob[13], blob[14]]) as usize,
+    );
+    self.set_reverse_animation(blob[16] & 0b010 != 0);
+    self.set_wrap_manual_input(blob[16] & 0b100 != 0);
+    self.set_inverted(blob[16] & 0b1000 != 0);
+    self.set_off_mode(match blob[17] {
+      0x01 => OffMode::Fade { frames: blob[18] },
+      0x02 => OffMode::KeepLastFrame,
+      _ => OffMode::Clear,
+    });
+    self.set_idle_color(RGBPixel::new(blob[19], blob[20], blob[21]));
+    self.set_on(blob[16] & 0b001 != 0);
+    self.set_hue_shift(u16::from_be_bytes([blob[22], blob[23]]));
+    self.set_saturation(f32_at(24));
+    self.set_value(f32_at(28));
+    self.set_brightness_profile(f32_at(32), f32_at(36));
+    self.set_gamma(f32_at(40));
+    self.set_reset_us(u16::from_be_bytes([blob[44], blob[45]]));
+    self.set_soft_start(u16::from_be_bytes([blob[48], blob[49]]));
+    // This runs at boot, which counts as turning on
+    if self.is_on {
+      self.soft_start_left = self.soft_start_frames;
+    }
+    self.raw_value_changed = true;
+    true
+  }
+
+  /// Write the reply frame for a command that expects one into `buffer`.
+  /// `status` holds diagnostics from outside the strip, e.g. the serial parser.
+  /// Returns the number of bytes written, 0 if the command has no reply.
+  pub fn encode_response(&self, command: &SerialCommand, status: &Status, buffer: &mut [u8]) -> usize {
+    match command.action {
+      0x06 => encode_version(buffer),
+      0x08 => encode_status(status, buffer),
+      0x0F => {
+        let start = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        let count = u16::from_be_bytes([command.data[2], command.data[3]]) as usize;
+        encode_pixels(&self.pixels, start, count, buffer)
+      },
+      0x11 => encode_strip_info(self.update_start, self.num_leds_to_update, buffer),
+      _ => 0,
+    }
+  }
+
+  /// Write chunk `sequence` of the pixel dump reply (the first active_len pixels, as rendered by the setting)
+  /// into `buffer`. The reply is split into chunks so it always fits in frames however long the strip is,
+  /// call this with 0, 1, 2... until it returns 0 and send each one.
+  pub fn encode_pixel_dump(&self, sequence: usize, buffer: &mut [u8]) -> usize {
+    encode_pixels_chunk(&self.pixels[..self.active_len], sequence, buffer)
+  }
+
+  /// Applies a SerialCommand modifying the LED strip settings or individual pixels.
+  /// Same as try_apply_command(), nothing is changed if it returns an error.
+  pub fn apply_command(&mut self, command: &SerialCommand) -> Result<(), CommandError> {
+    self.try_apply_command(command)
+  }
+
+  /// Checks the whole command (IDs, options, indices, every sub-command of a compound command)
+  /// before changing anything, so a command that returns an error has no effect at all.
+  /// Manual input running past the end of the strip is still applied up to the end.
+  pub fn try_apply_command(&mut self, command: &SerialCommand) -> Result<(), CommandError> {
+    let command = command.view();
+    self.validate_command(command, self.wrap_manual_input)?;
+    self.apply_validated_command(command)
+  }
+
+  /// Applies a command that passed validate_command()
+  fn apply_validated_command(&mut self, command: CommandView<'_>) -> Result<(), CommandError> {
+    // Keepalive, only resets the host timeout in main.rs. Returns before the overlay is touched
+    if command.action == 0x00 {
+      return Ok(());
+    }
+    // Undo the overlay first, otherwise restoring it later would overwrite pixels set here
+    if self.restore_overlay() {
+      self.raw_value_changed = true;
+    }
+    // Mark if raw values have changed
+    match command.action {
+      0x01 | 0x02 | 0x04 | 0x07 | 0x09 | 0x0A | 0x0B | 0x10 | 0x12 | 0x13 => {
+        self.raw_value_changed = true;
+      },
+      _ => {}
+    }
+    // Actually apply the command
+    match command.action {
+      0x01 => { // Set on / off
+        let state = command.data[0];
+        self.set_on(state != 0);
+      },
+      0x02 => { // Set value
+        let value_id = command.data[0];
+        match value_id {
+          0x00 => { // Global brightness
+            let brightness = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_brightness(brightness);
+          },
+          0x01 => { // Phase step
+            let phase_step = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_phase_step(phase_step);
+          },
+          0x02 => { // Num LEDs to update
+            let num_leds = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+            self.set_update_window(self.update_start, num_leds);
+          },
+          0x03 => { // Frames per second
+            let fps = command.data[1];
+            self.set_frames_per_second(fps);
+          },
+          0x04 => { // Reverse animation
+            let reverse = command.data[1] != 0;
+            self.set_reverse_animation(reverse);
+          },
+          0x05 => { // Phase
+            let phase = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_phase(phase);
+          },
+          0x06 => { // Hue shift
+            let degrees = u16::from_be_bytes([command.data[1], command.data[2]]);
+            self.set_hue_shift(degrees);
+          },
+          0x07 => { // Saturation
+            let saturation = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_saturation(saturation);
+          },
+          0x08 => { // Value
+            let value = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_value(value);
+          },
+          0x09 => { // Off mode
+            let mode = match command.data[1] {
+              0x00 => OffMode::Clear,
+              0x01 => OffMode::Fade { frames: command.data[2] },
+              0x02 => OffMode::KeepLastFrame,
+              // Unknown off mode, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_off_mode(mode);
+          },
+          0x0A => { // Wrap manual input
+            let wrap = command.data[1] != 0;
+            self.set_wrap_manual_input(wrap);
+          },
+          0x0B => { // Idle color
+            self.set_idle_color(RGBPixel::new(command.data[1], command.data[2], command.data[3]));
+          },
+          0x0C => { // Overlay
+            let overlay = match command.data[1] {
+              0x00 => None,
+              0x01 => Some(Overlay::Sparkle { rate: command.data[2] }),
+              // Unknown overlay, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_overlay(overlay);
+          },
+          0x0D => { // Reset time
+            let reset_us = u16::from_be_bytes([command.data[1], command.data[2]]);
+            self.set_reset_us(reset_us);
+          },
+          0x0E => { // Animation speed
+            let hz = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_animation_hz(hz);
+          },
+          0x0F => { // Brightness profile
+            let start = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            let end = f32::from_be_bytes([
+              command.data[5],
+              command.data[6],
+              command.data[7],
+              command.data[8],
+            ]);
+            self.set_brightness_profile(start, end);
+          },
+          0x10 => { // Gamma
+            let gamma = f32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_gamma(gamma);
+          },
+          0x11 => { // Update window start
+            let start = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+            self.set_update_window(start, self.num_leds_to_update);
+          },
+          0x12 => { // Host timeout
+            let timeout_ms = u32::from_be_bytes([
+              command.data[1],
+              command.data[2],
+              command.data[3],
+              command.data[4],
+            ]);
+            self.set_host_timeout(timeout_ms);
+          },
+          0x13 => { // Breathing curve
+            let easing = match command.data[1] {
+              0x00 => Easing::Sine,
+              0x01 => Easing::Triangle,
+              0x02 => Easing::QuadInOut,
+              0x03 => Easing::Exponential,
+              // Unknown curve, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_breathing_easing(easing);
+          },
+          0x14 => { // Brightness clipping
+            let preserve_hue = command.data[1] != 0;
+            self.set_preserve_hue(preserve_hue);
+          },
+          0x15 => { // Inverted data line
+            let inverted = command.data[1] != 0;
+            self.set_inverted(inverted);
+          },
+          0x16 => { // Channel floor
+            self.set_channel_floor(command.data[1]);
+          },
+          0x17 => { // Dither brightness
+            let dither = command.data[1] != 0;
+            self.set_dither_brightness(dither);
+          },
+          0x18 => { // Chase direction
+            let reverse = command.data[1] != 0;
+            let start = u16::from_be_bytes([command.data[2], command.data[3]]);
+            self.set_chase(reverse, start);
+          },
+          0x19 => { // Segment color format
+            let start = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+            let format = match command.data[3] {
+              0x00 => ColorFormat::Grb,
+              0x01 => ColorFormat::Grbw,
+              0xFF => { // Back to GRB for the whole strip
+                self.clear_segments();
+                return Ok(());
+              },
+              // Unknown format, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[3])),
+            };
+            // Ignored once all MAX_SEGMENTS are in use
+            self.set_segment_format(start, format);
+          },
+          0x1A => { // Render pipeline stages
+            self.set_render_pipeline(RenderPipeline::from_bits(command.data[1]));
+          },
+          0x1B => { // Chip timing
+            let timing = match command.data[1] {
+              0x00 => ChipTiming::Ws2812b,
+              0x01 => ChipTiming::Ws2811,
+              0x02 => ChipTiming::Ws2815,
+              // Unknown chip, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_chip_timing(timing);
+          },
+          0x1C => { // Freeze
+            let frozen = command.data[1] != 0;
+            self.set_frozen(frozen);
+          },
+          0x1D => { // Active length
+            let len = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+            self.set_active_len(len);
+          },
+          0x1E => { // Brightness curve
+            let perceptual = match command.data[1] {
+              0x00 => false,
+              0x01 => true,
+              // Unknown curve, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_perceptual_brightness(perceptual);
+          },
+          0x1F => { // Color vision mode
+            let mode = match command.data[1] {
+              0x00 => ColorVisionMode::None,
+              0x01 => ColorVisionMode::Protanopia,
+              0x02 => ColorVisionMode::Deuteranopia,
+              0x03 => ColorVisionMode::Tritanopia,
+              // Unknown mode, validate_command() rejects it first
+              _ => return Err(CommandError::UnknownOption(command.data[1])),
+            };
+            self.set_color_vision_mode(mode);
+          },
+          0x20 => { // Soft start
+            let frames = u16::from_be_bytes([command.data[1], command.data[2]]);
+            self.set_soft_start(frames);
+          },
+          0x21 => { // White balance
+            self.set_white_balance(RGBPixel::new(command.data[1], command.data[2], command.data[3]));
+          },
+          0x22 => { // Rainbow speed modulation
+            let depth = command.data[1] as f32 / 255.0;
+            let period = u16::from_be_bytes([command.data[2], command.data[3]]);
+            self.set_speed_modulation(depth, period);
+          },
+          // Unknown value ID, validate_command() rejects it first
+          _ => return Err(CommandError::UnknownValue(value_id)),
+        }
+      },
+      0x03 => { // Set StripSetting
+        let Some(setting) = Self::parse_setting(command) else {
+          // Unknown setting, validate_command() rejects it first
+          return Err(CommandError::UnknownSetting(command.data[0]));
+        };
+        // Optional trailing brightness byte, the brightness is kept when it is left out
+        if let Some(brightness) = command.setting_brightness() {
+          self.set_brightness(brightness);
+        }
+        self.set_setting(setting);
+      },
+      0x04 => { // Manual color input
+        let start_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        self.check_index(start_index)?;
+        self.set_setting(StripSetting::Custom);
+        self.write_manual_rgb(start_index, &command.data[2..(command.length as usize)]);
+      },
+      0x05 => { // Compound command
+        // All sub-commands were validated together, so either all of them are applied or none
+        let mut offset = 0;
+        while let Some((sub, next)) = command.sub_command_at(offset) {
+          self.apply_validated_command(sub)?;
+          offset = next;
+        }
+      },
+      0x07 => { // Manual HSV input
+        let start_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        let hsv_data = &command.data[2..(command.length as usize)];
+        let num_leds = hsv_data.len() / 4;
+        self.check_index(start_index)?;
+
+        self.set_setting(StripSetting::Custom);
+        self.hsv_mode = true;
+
+        for i in 0..num_leds {
+          let Some(led_index) = self.manual_input_index(start_index + i) else {
+            break; // Don't exceed strip bounds
+          };
+          let offset = i * 4;
+          let hue = u16::from_be_bytes([hsv_data[offset], hsv_data[offset + 1]]);
+          self.set_hsv_pixel(led_index, (hue, hsv_data[offset + 2], hsv_data[offset + 3]));
+        }
+      },
+      0x09 => { // RLE color input
+        let mut led_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        let runs = &command.data[2..(command.length as usize)];
+        if led_index >= NUM_LEDS {
+          return Err(CommandError::IndexOutOfRange(led_index));
+        }
+
+        self.set_setting(StripSetting::Custom);
+
+        for run in runs.chunks_exact(4) {
+          // Same as Fill, validate_rle() rejects runs past the end of the strip so they never wrap around
+          self.fill_range(led_index, run[0] as usize, RGBPixel::new(run[1], run[2], run[3]));
+          led_index += run[0] as usize;
+        }
+      },
+      0x0A | 0x0B => { // Brightness up / down
+        // Optional step size in hundredths
+        let step = if command.length >= 1 {
+          command.data[0] as f32 / 100.0
+        } else {
+          BRIGHTNESS_STEP
+        };
+        if command.action == 0x0A {
+          self.brightness_up(step);
+        } else {
+          self.brightness_down(step);
+        }
+      },
+      0x0C => { // Host loss fallback
+        // No payload turns the strip off, otherwise it's a Set StripSetting payload
+        if command.length == 0 {
+          self.set_host_fallback(None);
+        } else if let Some(setting) = Self::parse_setting(command) {
+          self.set_host_fallback(Some(setting));
+        } else {
+          return Err(CommandError::UnknownSetting(command.data[0]));
+        }
+      },
+      0x0D => { // Scroll buffer upload
+        let len = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        let start = u16::from_be_bytes([command.data[2], command.data[3]]) as usize;
+        let mut chunk = [RGBPixel::off(); MAX_READ_PIXELS];
+        let color_data = &command.data[4..(command.length as usize)];
+        for (slot, rgb) in chunk.iter_mut().zip(color_data.chunks_exact(3)) {
+          *slot = RGBPixel::new(rgb[0], rgb[1], rgb[2]);
+        }
+        self.set_scroll_pixels(len, start, &chunk[..color_data.len() / 3]);
+      },
+      0x10 => { // Manual color input with format
+        let start_index = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+        self.check_index(start_index)?;
+        self.set_setting(StripSetting::Custom);
+        match command.data[0] {
+          0x00 => self.write_manual_rgb(start_index, &command.data[3..(command.length as usize)]),
+          0x01 => {
+            let count = u16::from_be_bytes([command.data[3], command.data[4]]) as usize;
+            let pixel = RGBPixel::new(command.data[5], command.data[6], command.data[7]);
+            self.fill_range(start_index, count, pixel);
+          },
+          _ => return Err(CommandError::UnknownOption(command.data[0])), // Unknown format, validated before this
+        }
+      },
+      0x12 => { // Fill
+        let start_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        let count = u16::from_be_bytes([command.data[2], command.data[3]]) as usize;
+        let pixel = RGBPixel::new(command.data[4], command.data[5], command.data[6]);
+        self.check_index(start_index)?;
+        self.set_setting(StripSetting::Custom);
+        self.fill_range(start_index, count, pixel);
+      },
+      0x13 => { // Mask upload
+        let start_index = u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+        if start_index >= NUM_LEDS {
+          return Err(CommandError::IndexOutOfRange(start_index));
+        }
+        self.set_mask(start_index, &command.data[2..(command.length as usize)]);
+      },
+      0x14 => { // Refresh
+        self.request_refresh();
+      },
+      0x15 => { // Rotate
+        let offset = i16::from_be_bytes([command.data[0], command.data[1]]);
+        self.shift(offset as i32);
+        // Custom keeps the rotated pixels, an animation would draw over them
+        self.set_setting(StripSetting::Custom);
+      },
+      0x06 | 0x08 | 0x0F | 0x11 => {
+        // Queries, answered by encode_response()
+      },
+      0x0E => {
+        // Select strip, handled in main.rs
+      },
+      0x16 => {
+        // Pixel dump, answered with encode_pixel_dump() in main.rs
+      },
+      // Unknown command, validate_command() rejects it first
+      _ => return Err(CommandError::UnknownAction(command.action)),
+    }
+    Ok(())
+  }
+
+  /// Returns the error apply_validated_command() would return for this command, without changing anything.
+  /// Payload lengths were already checked by SerialCommand.
+  /// `wrap_manual_input` is passed in because an earlier sub-command of a compound command can change it.
+  fn validate_command(&self, command: CommandView<'_>, wrap_manual_input: bool) -> Result<(), CommandError> {
+    // Only read for the actions that have one, the payload may be shorter than 2 bytes otherwise
+    let start_index = || u16::from_be_bytes([command.data[0], command.data[1]]) as usize;
+    let check_index = |index: usize| {
+      if index >= NUM_LEDS && !wrap_manual_input {
+        return Err(CommandError::IndexOutOfRange(index));
+      }
+      Ok(())
+    };
+    match command.action {
+      0x00 | 0x01 | 0x06 | 0x08 | 0x0A | 0x0B | 0x0D | 0x0E | 0x0F | 0x11 | 0x14 | 0x15 | 0x16 => Ok(()),
+      0x02 => { // Set value
+        // Lengths were already checked, so this only fails on the f32s SerialCommand checks
+        if command.data[0] <= 0x22 && !command.validate_length_with_action() {
+          return Err(CommandError::InvalidNumber(command.data[0]));
+        }
+        // The highest valid option for values that pick from a list
+        let (option, max_option) = match command.data[0] {
+          0x09 => (command.data[1], 0x02), // Off mode
+          0x0C => (command.data[1], 0x01), // Overlay
+          0x13 => (command.data[1], 0x03), // Breathing curve
+          0x19 if command.data[3] == 0xFF => return Ok(()), // Segment color format, back to GRB
+          // Segment color format, GRBW only fits in pulse_data with the rgbw feature
+          0x19 => (command.data[3], if cfg!(feature = "rgbw") { 0x01 } else { 0x00 }),
+          0x1B => (command.data[1], 0x02), // Chip timing
+          0x1E => (command.data[1], 0x01), // Brightness curve
+          0x1F => (command.data[1], 0x03), // Color vision mode
+          0x00..=0x22 => return Ok(()),
+          value_id => return Err(CommandError::UnknownValue(value_id)),
+        };
+        if option > max_option {
+          return Err(CommandError::UnknownOption(option));
+        }
+        Ok(())
+      },
+      0x03 => {
+        if Self::parse_setting(command).is_none() {
+          return Err(CommandError::UnknownSetting(command.data[0]));
+        }
+        if !command.validate_length_with_action() {
+          return Err(CommandError::InvalidNumber(command.data[0]));
+        }
+        Ok(())
+      },
+      0x0C => {
+        // No payload is valid, it turns the strip off
+        if command.length > 0 && Self::parse_setting(command).is_none() {
+          return Err(CommandError::UnknownSetting(command.data[0]));
+        }
+        if !command.validate_length_with_action() {
+          return Err(CommandError::InvalidNumber(command.data[0]));
+        }
+        Ok(())
+      },
+      0x04 | 0x07 | 0x12 => check_index(start_index()),
+      0x09 | 0x13 => {
+        // These don't wrap around
+        if start_index() >= NUM_LEDS {
+          return Err(CommandError::IndexOutOfRange(start_index()));
+        }
+        Ok(())
+      },
+      0x10 => match command.data[0] {
+        0x00 | 0x01 => {
+          let start_index = u16::from_be_bytes([command.data[1], command.data[2]]) as usize;
+          check_index(start_index)
+        },
+        format => Err(CommandError::UnknownOption(format)),
+      },
+      0x05 => {
+        let mut wrap_manual_input = wrap_manual_input;
+        let mut offset = 0;
+        while let Some((sub, next)) = command.sub_command_at(offset) {
+          // Compound commands cannot be nested. The parser rejects them already, a hand-built command might not
+          if sub.action == 0x05 {
+            return Err(CommandError::UnknownAction(sub.action));
+          }
+          self.validate_command(sub, wrap_manual_input)?;
+          // Wrap manual input, applied before the sub-commands after it
+          if sub.action == 0x02 && sub.data[0] == 0x0A {
+            wrap_manual_input = sub.data[1] != 0;
+          }
+          offset = next;
+        }
+        Ok(())
+      },
+      action => Err(CommandError::UnknownAction(action)),
+    }
+  }
+
+  /// Error unless manual input starting at `index` lands on the strip
+  fn check_index(&self, index: usize) -> Result<(), CommandError> {
+    match self.manual_input_index(index) {
+      Some(_) => Ok(()),
+      None => Err(CommandError::IndexOutOfRange(index)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::command::{MAX_SUB_COMMANDS, SHORTEST_SETTINGS};
+
+  fn rgb(pixel: &RGBPixel) -> (u8, u8, u8) {
+    (pixel.r, pixel.g, pixel.b)
+  }
+
+  fn set_setting(strip: &mut LEDStrip, payload: &[u8]) -> Result<(), CommandError> {
+    strip.apply_command(&SerialCommand::from_payload(0x03, payload))
+  }
+
+  /// A compound command (action 0x05) made of `subs`, each (action, payload)
+  fn compound(subs: &[(u8, &[u8])]) -> SerialCommand {
+    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+    let mut len = 0;
+    for (action, data) in subs {
+      payload[len] = *action;
+      payload[len + 1..len + 3].copy_from_slice(&(data.len() as u16).to_be_bytes());
+      payload[len + 3..len + 3 + data.len()].copy_from_slice(data);
+      len += 3 + data.len();
+    }
+    SerialCommand::from_payload(0x05, &payload[..len])
+  }
+
+  #[test]
+  fn pixel_add_saturates_and_scale_rounds_down() {
+    let base = RGBPixel::new(200, 100, 0);
+    assert_eq!(rgb(&base.saturating_add(&RGBPixel::new(100, 100, 100))), (255, 200, 100));
+    assert_eq!(rgb(&base.scale(0.5)), (100, 50, 0));
+    assert_eq!(rgb(&RGBPixel::new(3, 255, 255).scale(0.5)), (1, 127, 127));
+    // Clamped both ways
+    assert_eq!(rgb(&base.scale(2.0)), (255, 200, 0));
+    assert_eq!(rgb(&base.scale(-1.0)), (0, 0, 0));
+  }
+
+  #[test]
+  fn compound_brightness_and_setting() {
+    let mut strip = LEDStrip::new();
+    let mut brightness = [0x00; 5];
+    brightness[1..].copy_from_slice(&0.5f32.to_be_bytes());
+    let command = compound(&[(0x02, &brightness), (0x03, &[0x02, 200, 100, 50])]);
+    assert!(command.validate_length_with_action());
+    strip.apply_command(&command).unwrap();
+    assert_eq!(strip.get_brightness(), 0.5);
+    assert!(matches!(strip.setting, StripSetting::SolidColor { r: 200, g: 100, b: 50 }));
+    strip.update_pixels();
+    assert_eq!(rgb(&strip.pixels[0]), (100, 50, 25));
+  }
+
+  #[test]
+  fn self_test_cycles_colors_then_settles() {
+    let mut strip = LEDStrip::new();
+    strip.set_brightness(1.0);
+    set_setting(&mut strip, &[0x08]).unwrap();
+    for color in [RGBPixel::red(), RGBPixel::green(), RGBPixel::blue(), RGBPixel::white()] {
+      for _ in 0..SELF_TEST_FRAMES_PER_COLOR {
+        strip.update_pixels();
+        assert!(matches!(strip.setting, StripSetting::SelfTest));
+        assert!(strip.pixels.iter().all(|&pixel| pixel == color));
+      }
+    }
+    // Done, everything off and back to Custom so it stays that way
+    strip.update_pixels();
+    assert!(matches!(strip.setting, StripSetting::Custom));
+    assert!(strip.pixels.iter().all(|&pixel| pixel == RGBPixel::off()));
+    assert!(!strip.update_pixels());
+  }
+
+  #[test]
+  fn fill_sets_a_range_to_one_color() {
+    let mut strip = LEDStrip::new();
+    strip.apply_command(&SerialCommand::from_payload(0x04, &[0x00, 0x09, 1, 2, 3])).unwrap();
+    strip.apply_command(&SerialCommand::from_payload(0x04, &[0x00, 0x3C, 4, 5, 6])).unwrap();
+    // LEDs 10 to 59 red
+    strip.apply_command(&SerialCommand::from_payload(0x12, &[0x00, 0x0A, 0x00, 0x32, 255, 0, 0])).unwrap();
+    assert!(strip.pixels[10..60].iter().all(|&pixel| pixel == RGBPixel::red()));
+    assert!(strip.pixels[9] == RGBPixel::new(1, 2, 3));
+    assert!(strip.pixels[60] == RGBPixel::new(4, 5, 6));
+    // The same range as a run fill with manual color input with format
+    strip.apply_command(&SerialCommand::from_payload(0x10, &[0x01, 0x00, 0x0A, 0x00, 0x32, 0, 255, 0])).unwrap();
+    assert!(strip.pixels[10..60].iter().all(|&pixel| pixel == RGBPixel::green()));
+    assert!(strip.pixels[9] == RGBPixel::new(1, 2, 3));
+    assert!(strip.pixels[60] == RGBPixel::new(4, 5, 6));
+  }
+
+  #[test]
+  fn rle_input_expands_runs_into_pixels() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x02, 1, 2, 3]).unwrap();
+    // From LED 5: 3 red, 1 green, 200 blue
+    let payload = [0x00, 0x05, 3, 255, 0, 0, 1, 0, 255, 0, 200, 0, 0, 255];
+    strip.apply_command(&SerialCommand::from_payload(0x09, &payload)).unwrap();
+    assert!(matches!(strip.setting, StripSetting::Custom));
+    assert!(strip.pixels[..5].iter().all(|&pixel| pixel == RGBPixel::off()));
+    assert!(strip.pixels[5..8].iter().all(|&pixel| pixel == RGBPixel::red()));
+    assert!(strip.pixels[8] == RGBPixel::green());
+    assert!(strip.pixels[9..209].iter().all(|&pixel| pixel == RGBPixel::blue()));
+    assert!(strip.pixels[209..].iter().all(|&pixel| pixel == RGBPixel::off()));
+  }
+
+  #[test]
+  fn non_finite_phase_is_rejected() {
+    let mut strip = LEDStrip::new();
+    strip.set_phase(0.25);
+    strip.set_phase_step(0.01);
+    for value_id in [0x01, 0x05] {
+      for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        let mut payload = [value_id, 0, 0, 0, 0];
+        payload[1..].copy_from_slice(&bad.to_be_bytes());
+        let result = strip.apply_command(&SerialCommand::from_payload(0x02, &payload));
+        assert_eq!(result, Err(CommandError::InvalidNumber(value_id)));
+      }
+    }
+    assert_eq!(strip.get_phase(), 0.25);
+    assert_eq!(strip.phase_step, 0.01);
+    // The setters ignore them too
+    strip.set_phase(f32::NAN);
+    strip.set_phase_step(f32::INFINITY);
+    assert_eq!(strip.get_phase(), 0.25);
+    assert_eq!(strip.phase_step, 0.01);
+  }
+
+  #[test]
+  fn compound_sub_lengths_must_fill_the_payload() {
+    let valid = compound(&[(0x01, &[1]), (0x14, &[])]);
+    assert!(valid.validate_length_with_action());
+    let payload = &valid.data[..valid.length as usize];
+    // Cut off in the middle of the second sub-command's header
+    assert!(!SerialCommand::from_payload(0x05, &payload[..payload.len() - 1]).validate_length_with_action());
+    // A sub-command length running past the end of the payload
+    let mut overlong = [0u8; 16];
+    overlong[..payload.len()].copy_from_slice(payload);
+    overlong[2] = 2;
+    assert!(!SerialCommand::from_payload(0x05, &overlong[..payload.len()]).validate_length_with_action());
+    // A sub-command length too short for its action
+    assert!(!compound(&[(0x02, &[0x00, 0x3F])]).validate_length_with_action());
+    // Nothing in it, or nested
+    assert!(!SerialCommand::from_payload(0x05, &[]).validate_length_with_action());
+    assert!(!compound(&[(0x05, &[0x14, 0x00, 0x00])]).validate_length_with_action());
+    let mut strip = LEDStrip::new();
+    let nested = compound(&[(0x05, &[0x14, 0x00, 0x00])]);
+    assert_eq!(strip.apply_command(&nested), Err(CommandError::UnknownAction(0x05)));
+  }
+
+  #[test]
+  fn compound_sub_command_limit() {
+    let refresh: (u8, &[u8]) = (0x14, &[]);
+    assert!(compound(&[refresh; MAX_SUB_COMMANDS]).validate_length_with_action());
+    assert!(!compound(&[refresh; MAX_SUB_COMMANDS + 1]).validate_length_with_action());
+  }
+
+  #[test]
+  fn short_payloads_never_read_past_the_end() {
+    // Whatever passes the length check has to apply without reading past its payload,
+    // on its own and as the only sub-command of a compound command
+    let mut strip = LEDStrip::new();
+    for action in 0..=0x20u8 {
+      for first in 0..=0x25u8 {
+        for len in 0..=12 {
+          for fill in [0x00, 0x01, 0xFF] {
+            let mut payload = [fill; 12];
+            payload[0] = first;
+            let command = SerialCommand::from_payload(action, &payload[..len]);
+            if !command.validate_length_with_action() {
+              continue;
+            }
+            let _ = strip.apply_command(&command);
+            if action != 0x05 {
+              let _ = strip.apply_command(&compound(&[(action, &payload[..len])]));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[cfg(feature = "packed-pulses")]
+  #[test]
+  fn packed_chunks_make_up_the_frame() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    #[cfg(feature = "rgbw")]
+    strip.set_segment_format(100, ColorFormat::Grbw);
+    strip.set_update_window(7, 200);
+    strip.update_pixels();
+    strip.generate_pulse_data();
+    let mut frame = [Pulse::default(); PULSE_BUFFER_LEN];
+    let frame = strip.get_pulse_data(&mut frame);
+
+    let mut streamed = [Pulse::default(); PULSE_BUFFER_LEN];
+    let mut streamed_len = 0;
+    let mut chunk = [Pulse::default(); PACKED_CHUNK_LEN];
+    let mut index = 0;
+    while let Some(len) = strip.packed_chunk(index, &mut chunk) {
+      // Fits in the RMT memory of one channel with its end marker
+      assert!(len <= 48);
+      assert_eq!(chunk[len - 1], Pulse::end_marker());
+      streamed[streamed_len..streamed_len + len - 1].copy_from_slice(&chunk[..len - 1]);
+      streamed_len += len - 1;
+      index += 1;
+    }
+    assert_eq!(&streamed[..streamed_len], &frame[..frame.len() - 1]);
+  }
+
+  #[cfg(feature = "packed-pulses")]
+  #[test]
+  fn spi_data_encodes_the_update_window() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    strip.set_brightness(1.0);
+    strip.set_update_window(7, 10);
+    strip.update_pixels();
+    strip.generate_pulse_data();
+    let mut buffer = [0; SPI_BUFFER_LEN];
+    let spi = strip.try_get_spi_data(&mut buffer).unwrap();
+    assert_eq!(spi.len(), 10 * 3 * 3 + spi_reset_bytes(0));
+    for (byte, encoded) in strip.pulse_data[7 * 3..17 * 3].iter().zip(spi.chunks_exact(3)) {
+      assert_eq!(encoded, byte_to_spi(*byte));
+    }
+    assert!(spi[10 * 3 * 3..].iter().all(|&byte| byte == 0));
+
+    // The whole strip with the longest reset always fits
+    strip.set_update_window(0, NUM_LEDS);
+    strip.set_reset_us(MAX_RESET_US);
+    assert!(strip.try_get_spi_data(&mut buffer).is_ok());
+    assert!(matches!(
+      strip.try_get_spi_data(&mut buffer[..100]),
+      Err(PulseError::BufferTooSmall { provided: 100, .. })
+    ));
+  }
+
+  #[cfg(feature = "rgbw")]
+  #[test]
+  fn end_marker_follows_mixed_segments() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    assert!(strip.set_segment_format(10, ColorFormat::Grbw));
+    assert!(strip.set_segment_format(30, ColorFormat::Grb));
+    assert!(strip.set_segment_format(NUM_LEDS - 5, ColorFormat::Grbw));
+    strip.update_pixels();
+    strip.generate_pulse_data();
+    let mut buffer = [Pulse::default(); PULSE_BUFFER_LEN];
+    let frame = strip.get_pulse_data(&mut buffer);
+    let led_pulses = (NUM_LEDS - 25) * 24 + 25 * 32;
+    assert_eq!(frame.len(), led_pulses + strip.reset_pulse_count + 1);
+    assert_eq!(frame[frame.len() - 1], Pulse::end_marker());
+    assert!(frame[..frame.len() - 1].iter().all(|&pulse| pulse != Pulse::end_marker()));
+
+    // Only part of the strip, starting inside the first GRBW segment
+    strip.set_update_window(20, 15);
+    let frame = strip.get_pulse_data(&mut buffer);
+    assert_eq!(frame.len(), 10 * 32 + 5 * 24 + strip.reset_pulse_count + 1);
+    assert_eq!(frame[frame.len() - 1], Pulse::end_marker());
+  }
+
+  #[cfg(not(feature = "rgbw"))]
+  #[test]
+  fn grbw_segments_need_the_rgbw_feature() {
+    let mut strip = LEDStrip::new();
+    assert!(!strip.set_segment_format(10, ColorFormat::Grbw));
+    assert!(strip.set_segment_format(10, ColorFormat::Grb));
+    assert_eq!(
+      strip.try_apply_command(&SerialCommand::from_payload(0x02, &[0x19, 0x00, 0x0A, 0x01])),
+      Err(CommandError::UnknownOption(0x01))
+    );
+  }
+
+  #[test]
+  fn hsv_input_only_renders_active_leds() {
+    let mut strip = LEDStrip::new();
+    strip.set_active_len(10);
+    // Start index 0, then 20 LEDs of red
+    let mut payload = [0; 2 + 20 * 4];
+    for led in payload[2..].chunks_exact_mut(4) {
+      led.copy_from_slice(&[0x00, 0x00, 255, 255]);
+    }
+    strip.apply_command(&SerialCommand::from_payload(0x07, &payload)).unwrap();
+    assert!(strip.update_pixels());
+    assert!(strip.pixels[..10].iter().all(|&pixel| pixel == RGBPixel::red()));
+    assert!(strip.pixels[10..].iter().all(|&pixel| pixel == RGBPixel::off()));
+  }
+
+  #[test]
+  fn rainbow_spans_the_update_window() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    strip.set_phase_step(0.0);
+    strip.set_brightness(1.0);
+    strip.set_update_window(0, 100);
+    strip.update_pixels();
+    // One full cycle over the 100 LEDs sent, halfway along is the opposite hue
+    assert!(strip.pixels[0] == hsv_to_rgb(0, 255, 255));
+    assert!(strip.pixels[25] == hsv_to_rgb(90, 255, 255));
+    assert!(strip.pixels[50] == hsv_to_rgb(180, 255, 255));
+    assert!(strip.pixels[99] == hsv_to_rgb(356, 255, 255));
+
+    // A window further along starts the cycle at its first LED
+    strip.set_update_window(40, 100);
+    strip.update_pixels();
+    assert!(strip.pixels[40] == hsv_to_rgb(0, 255, 255));
+    assert!(strip.pixels[90] == hsv_to_rgb(180, 255, 255));
+  }
+
+  #[test]
+  fn rainbow_chase_preserves_hue() {
+    let mut strip = LEDStrip::new();
+    // Every LED lit and the phase held still, so the frames only differ by brightness
+    set_setting(&mut strip, &[0x04, 0x3F, 0x80, 0x00, 0x00, 1]).unwrap();
+    strip.set_phase_step(0.0);
+    strip.set_brightness(1.0);
+    strip.update_pixels();
+    let full = strip.pixels;
+    // The rainbow's brightest channel is already 255, so with preserve_hue nothing can get brighter
+    strip.set_brightness(2.0);
+    strip.set_preserve_hue(true);
+    strip.update_pixels();
+    assert!(strip.pixels == full);
+    // Without it the other channels are doubled and the hues shift towards white
+    strip.set_preserve_hue(false);
+    strip.update_pixels();
+    assert!(strip.pixels != full);
+  }
+
+  #[test]
+  fn rainbow_sweep_dithers_brightness() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x0A, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    // Reveals the whole rainbow after the first frame
+    strip.set_phase_step(1.0);
+    strip.update_pixels();
+    strip.set_brightness(1.0);
+    strip.update_pixels();
+    let full = strip.pixels;
+    strip.set_brightness(0.4);
+    let mut frames = [[RGBPixel::off(); NUM_LEDS]; 8];
+    for dither in [false, true] {
+      strip.set_dither_brightness(dither);
+      for frame in frames.iter_mut() {
+        strip.update_pixels();
+        *frame = strip.pixels;
+      }
+      assert_eq!(frames.iter().any(|frame| *frame != frames[0]), dither);
+      // Dithering only ever rounds up instead of down
+      for frame in frames.iter() {
+        for (pixel, full) in frame.iter().zip(full.iter()) {
+          for (c, full) in [(pixel.r, full.r), (pixel.g, full.g), (pixel.b, full.b)] {
+            let scaled = full as f32 * 0.4;
+            assert!(c as f32 >= scaled.floor() && c as f32 <= scaled.floor() + 1.0);
+          }
+        }
+      }
+    }
+  }
+
+  /// A strip with most of the saved values changed from the defaults
+  fn configured_strip() -> LEDStrip {
+    let mut strip = LEDStrip::new();
+    strip.set_brightness(0.3);
+    strip.set_frames_per_second(30);
+    strip.set_gamma(2.2);
+    strip.set_hue_shift(1200);
+    strip.set_reverse_animation(true);
+    strip.set_off_mode(OffMode::Fade { frames: 12 });
+    strip.set_idle_color(RGBPixel::new(1, 2, 3));
+    strip.set_update_window(4, 100);
+    strip
+  }
+
+  #[test]
+  fn config_snapshot_round_trip() {
+    let blob = configured_strip().config_snapshot();
+    assert!(blob != LEDStrip::new().config_snapshot());
+    let mut strip = LEDStrip::new();
+    assert!(strip.config_restore(&blob));
+    assert!(strip.config_snapshot() == blob);
+  }
+
+  #[test]
+  fn config_restore_rejects_bad_crc() {
+    let defaults = LEDStrip::new().config_snapshot();
+    let mut blob = configured_strip().config_snapshot();
+    blob[1] ^= 0x01;
+    let mut strip = LEDStrip::new();
+    assert!(!strip.config_restore(&blob));
+    assert!(strip.config_snapshot() == defaults);
+    // Erased flash
+    assert!(!strip.config_restore(&[0xFF; CONFIG_SNAPSHOT_LEN]));
+    assert!(strip.config_snapshot() == defaults);
+  }
+
+  #[test]
+  fn config_restore_rejects_other_versions_and_lengths() {
+    let defaults = LEDStrip::new().config_snapshot();
+    let blob = configured_strip().config_snapshot();
+    let mut strip = LEDStrip::new();
+    // Another version, with a CRC that matches it
+    let mut other_version = blob;
+    other_version[0] = CONFIG_SNAPSHOT_VERSION - 1;
+    let crc = SerialCommand::checksum_of(other_version[0], &other_version[1..CONFIG_SNAPSHOT_LEN - 2]);
+    other_version[CONFIG_SNAPSHOT_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+    assert!(!strip.config_restore(&other_version));
+    assert!(!strip.config_restore(&blob[..CONFIG_SNAPSHOT_LEN - 1]));
+    let mut longer = [0u8; CONFIG_SNAPSHOT_LEN + 1];
+    longer[..CONFIG_SNAPSHOT_LEN].copy_from_slice(&blob);
+    assert!(!strip.config_restore(&longer));
+    assert!(strip.config_snapshot() == defaults);
+  }
+
+  #[test]
+  fn off_setting_renders_black_and_stays_on() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x02, 200, 100, 50, 255]).unwrap();
+    strip.update_pixels();
+    assert!(strip.pixels.iter().all(|pixel| *pixel == RGBPixel::new(200, 100, 50)));
+    set_setting(&mut strip, &[0x00]).unwrap();
+    assert!(matches!(strip.setting, StripSetting::Off));
+    assert!(strip.update_pixels());
+    assert!(strip.pixels.iter().all(|pixel| *pixel == RGBPixel::off()));
+    assert!(strip.is_on);
+    // Nothing changes in the frames after
+    assert!(!strip.update_pixels());
+  }
+
+  #[test]
+  fn every_command_error() {
+    let index = (NUM_LEDS as u16).to_be_bytes();
+    let nan = f32::NAN.to_be_bytes();
+    let cases: [(u8, &[u8], CommandError); 8] = [
+      (0x7E, &[], CommandError::UnknownAction(0x7E)),
+      (0x02, &[0x7F, 0x00], CommandError::UnknownValue(0x7F)),
+      (0x03, &[0x7F], CommandError::UnknownSetting(0x7F)),
+      (0x02, &[0x09, 0x05, 0x00], CommandError::UnknownOption(0x05)), // Off mode
+      (0x04, &[index[0], index[1], 10, 20, 30], CommandError::IndexOutOfRange(NUM_LEDS)), // Manual color input
+      (0x09, &[index[0], index[1], 1, 10, 20, 30], CommandError::IndexOutOfRange(NUM_LEDS)), // RLE color input
+      (0x02, &[0x00, nan[0], nan[1], nan[2], nan[3]], CommandError::InvalidNumber(0x00)), // Global brightness
+      (0x03, &[0x03, nan[0], nan[1], nan[2], nan[3]], CommandError::InvalidNumber(0x03)), // RainbowCycle
+    ];
+    for (action, payload, error) in cases {
+      let mut strip = configured_strip();
+      let before = strip.config_snapshot();
+      assert_eq!(strip.try_apply_command(&SerialCommand::from_payload(action, payload)), Err(error));
+      // Nothing was applied
+      assert!(strip.config_snapshot() == before);
+      assert!(matches!(strip.setting, StripSetting::Custom));
+    }
+  }
+
+  #[test]
+  fn apply_every_setting_with_and_without_brightness() {
+    for payload in SHORTEST_SETTINGS {
+      let id = payload[0];
+      let mut strip = LEDStrip::new();
+      set_setting(&mut strip, &[0x02, 1, 2, 3, 0x80]).unwrap();
+      // Without the byte the brightness stays
+      set_setting(&mut strip, payload).unwrap();
+      assert_eq!(strip.get_brightness(), 0x80 as f32 / 255.0, "setting {id:#04x}");
+      let mut with = [0u8; 16];
+      with[..payload.len()].copy_from_slice(payload);
+      with[payload.len()] = 0x33;
+      set_setting(&mut strip, &with[..payload.len() + 1]).unwrap();
+      assert_eq!(strip.get_brightness(), 0x33 as f32 / 255.0, "setting {id:#04x}");
+      strip.update_pixels();
+    }
+  }
+
+  #[test]
+  fn parse_breathing_lengths() {
+    let parse = |payload: &[u8]| LEDStrip::parse_setting(SerialCommand::from_payload(0x03, payload).view());
+    for (payload, expected) in [
+      (&[0x01, 10, 20, 30][..], (0, 255)),
+      (&[0x01, 10, 20, 30, 51][..], (0, 255)),
+      (&[0x01, 10, 20, 30, 40, 200][..], (40, 200)),
+      (&[0x01, 10, 20, 30, 40, 200, 102][..], (40, 200)),
+    ] {
+      match parse(payload) {
+        Some(StripSetting::Breathing { r: 10, g: 20, b: 30, min, max }) => assert_eq!((min, max), expected),
+        _ => panic!("{payload:?} didn't parse as Breathing"),
+      }
+    }
+  }
+
+  #[test]
+  fn breathing_short_form_sets_brightness() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x01, 10, 20, 30, 51]).unwrap();
+    assert_eq!(strip.get_brightness(), 51.0 / 255.0);
+    set_setting(&mut strip, &[0x01, 10, 20, 30, 40, 200]).unwrap();
+    assert_eq!(strip.get_brightness(), 51.0 / 255.0);
+    set_setting(&mut strip, &[0x01, 10, 20, 30, 40, 200, 102]).unwrap();
+    assert_eq!(strip.get_brightness(), 102.0 / 255.0);
+  }
+
+  #[test]
+  fn breathing_min_equal_max_is_steady() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x01, 200, 100, 50, 128, 128]).unwrap();
+    strip.update_pixels();
+    let first = rgb(&strip.pixels[0]);
+    assert_ne!(first, (0, 0, 0));
+    for _ in 0..200 {
+      strip.update_pixels();
+      assert_eq!(rgb(&strip.pixels[0]), first);
+    }
+  }
+
+  #[test]
+  fn frames_per_second_is_clamped() {
+    let mut strip = LEDStrip::new();
+    strip.set_frames_per_second(0);
+    assert_eq!(strip.get_frames_per_second(), 1);
+    strip.set_frames_per_second(255);
+    assert_eq!(strip.get_frames_per_second(), MAX_FRAMES_PER_SECOND);
+    // From a Set value command too
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x03, 0])).unwrap();
+    assert_eq!(strip.get_frames_per_second(), 1);
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x03, 60])).unwrap();
+    assert_eq!(strip.get_frames_per_second(), 60);
+  }
+
+  #[test]
+  fn negative_phase_step_wraps_below_zero() {
+    let mut strip = LEDStrip::new();
+    strip.set_phase(0.1);
+    strip.set_phase_step(-0.3);
+    strip.advance_phase();
+    assert!((strip.get_phase() - 0.8).abs() < 0.0001);
+    for _ in 0..20 {
+      strip.advance_phase();
+      assert!((0.0..1.0).contains(&strip.get_phase()));
+    }
+    // A step too small to leave 0.0 would round up to 1.0
+    strip.set_phase(0.0);
+    strip.set_phase_step(-1e-9);
+    strip.advance_phase();
+    assert!((0.0..1.0).contains(&strip.get_phase()));
+  }
+
+  #[test]
+  fn zero_saturation_is_grayscale_and_one_is_a_no_op() {
+    let render = |strip: &mut LEDStrip, buffer: &mut [Pulse; PULSE_BUFFER_LEN]| {
+      strip.update_pixels();
+      strip.generate_pulse_data();
+      strip.get_pulse_data(buffer).len()
+    };
+    let set_multiplier = |strip: &mut LEDStrip, id: u8, multiplier: f32| {
+      let bytes = multiplier.to_be_bytes();
+      strip.apply_command(&SerialCommand::from_payload(0x02, &[id, bytes[0], bytes[1], bytes[2], bytes[3]])).unwrap();
+    };
+    let mut colored = LEDStrip::new();
+    set_setting(&mut colored, &[0x02, 200, 100, 50, 255]).unwrap();
+    let mut plain = [Pulse::default(); PULSE_BUFFER_LEN];
+    let len = render(&mut colored, &mut plain);
+
+    // Saturation 0.0 keeps the value (the largest channel) on every channel
+    set_multiplier(&mut colored, 0x07, 0.0);
+    let mut gray = [Pulse::default(); PULSE_BUFFER_LEN];
+    render(&mut colored, &mut gray);
+    let mut expected = LEDStrip::new();
+    set_setting(&mut expected, &[0x02, 200, 200, 200, 255]).unwrap();
+    let mut expected_gray = [Pulse::default(); PULSE_BUFFER_LEN];
+    render(&mut expected, &mut expected_gray);
+    assert_eq!(gray[..len], expected_gray[..len]);
+
+    // Back to 1.0, with value 1.0, gives exactly the untouched frame
+    set_multiplier(&mut colored, 0x07, 1.0);
+    set_multiplier(&mut colored, 0x08, 1.0);
+    let mut restored = [Pulse::default(); PULSE_BUFFER_LEN];
+    render(&mut colored, &mut restored);
+    assert_eq!(restored[..len], plain[..len]);
+  }
+
+  #[test]
+  fn undersized_pulse_buffer_is_an_error() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x02, 10, 20, 30, 255]).unwrap();
+    strip.set_update_window(0, 10);
+    strip.update_pixels();
+    strip.generate_pulse_data();
+    let required = 10 * 24 + strip.reset_pulse_count + 1;
+    let mut buffer = [Pulse::default(); PULSE_BUFFER_LEN];
+    assert!(matches!(
+      strip.try_get_pulse_data(&mut buffer[..required - 1]),
+      Err(PulseError::BufferTooSmall { required: r, provided: p }) if r == required && p == required - 1
+    ));
+    // An exact fit is enough
+    let frame = strip.try_get_pulse_data(&mut buffer[..required]).ok().unwrap();
+    assert_eq!(frame.len(), required);
+    assert_eq!(frame[required - 1], Pulse::end_marker());
+  }
+
+  #[test]
+  fn manual_input_near_the_end_drops_or_wraps() {
+    let last = ((NUM_LEDS - 1) as u16).to_be_bytes();
+    let input = SerialCommand::from_payload(0x04, &[last[0], last[1], 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    // Clamped by default, the pixels past the end are dropped
+    let mut strip = LEDStrip::new();
+    strip.apply_command(&input).unwrap();
+    assert_eq!(rgb(&strip.pixels[NUM_LEDS - 1]), (1, 2, 3));
+    assert_eq!(rgb(&strip.pixels[0]), (0, 0, 0));
+    assert_eq!(rgb(&strip.pixels[1]), (0, 0, 0));
+
+    let mut strip = LEDStrip::new();
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x0A, 0x01])).unwrap();
+    strip.apply_command(&input).unwrap();
+    assert_eq!(rgb(&strip.pixels[NUM_LEDS - 1]), (1, 2, 3));
+    assert_eq!(rgb(&strip.pixels[0]), (4, 5, 6));
+    assert_eq!(rgb(&strip.pixels[1]), (7, 8, 9));
+  }
+
+  #[test]
+  fn clear_fills_the_idle_color() {
+    let mut strip = LEDStrip::new();
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x0B, 40, 20, 5])).unwrap();
+    assert!(strip.clear());
+    assert!(strip.pixels.iter().all(|pixel| *pixel == RGBPixel::new(40, 20, 5)));
+    // Already at the idle color, so nothing changes
+    assert!(!strip.clear());
+    // blackout() ignores the idle color
+    assert!(strip.blackout());
+    assert!(strip.pixels.iter().all(|pixel| *pixel == RGBPixel::off()));
+    assert!(!strip.blackout());
+  }
+
+  #[test]
+  fn gamma_command_rebuilds_the_table() {
+    let gamma = |value: f32| {
+      let bytes = value.to_be_bytes();
+      SerialCommand::from_payload(0x02, &[0x10, bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    let mut strip = LEDStrip::new();
+    strip.apply_command(&gamma(2.2)).unwrap();
+    assert_eq!(strip.get_gamma(), 2.2);
+    // 255 * (i / 255) ^ 2.2, rounded
+    assert_eq!(strip.gamma_lut[0], 0);
+    assert_eq!(strip.gamma_lut[64], 12);
+    assert_eq!(strip.gamma_lut[128], 56);
+    assert_eq!(strip.gamma_lut[255], 255);
+    // Non-positive gamma is rejected and leaves the table alone
+    for bad in [0.0, -1.0] {
+      assert_eq!(strip.apply_command(&gamma(bad)), Err(CommandError::InvalidNumber(0x10)));
+    }
+    assert_eq!(strip.get_gamma(), 2.2);
+    assert_eq!(strip.gamma_lut[128], 56);
+  }
+
+  #[test]
+  fn update_window_sends_exactly_its_leds() {
+    let mut strip = LEDStrip::new();
+    for (i, pixel) in strip.pixels.iter_mut().enumerate() {
+      *pixel = RGBPixel::new(i as u8, 255 - i as u8, (i * 3) as u8);
+    }
+    strip.generate_pulse_data();
+    let mut full = [Pulse::default(); PULSE_BUFFER_LEN];
+    let full_len = strip.get_pulse_data(&mut full).len();
+    let tail = &full[NUM_LEDS * 24..full_len];
+
+    // Start first, the count is clamped to what's left after it
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x11, 0x00, 100])).unwrap();
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x02, 0x00, 20])).unwrap();
+    assert_eq!(strip.get_update_start(), 100);
+    assert_eq!(strip.get_num_leds_to_update(), 20);
+    let mut buffer = [Pulse::default(); PULSE_BUFFER_LEN];
+    let window = strip.get_pulse_data(&mut buffer);
+    assert_eq!(window.len(), 20 * 24 + tail.len());
+    assert_eq!(window[..20 * 24], full[100 * 24..120 * 24]);
+    assert_eq!(window[20 * 24..], *tail);
+    assert_eq!(window[window.len() - 1], Pulse::end_marker());
+  }
+
+  #[test]
+  fn formatted_manual_input_run_fill_and_per_pixel() {
+    let mut strip = LEDStrip::new();
+    // Run fill: 50 LEDs from index 10
+    let run = SerialCommand::from_payload(0x10, &[0x01, 0x00, 10, 0x00, 50, 7, 8, 9]);
+    assert!(run.validate_length_with_action());
+    strip.apply_command(&run).unwrap();
+    assert!(matches!(strip.setting, StripSetting::Custom));
+    assert!(strip.pixels[10..60].iter().all(|&pixel| pixel == RGBPixel::new(7, 8, 9)));
+    assert!(strip.pixels[9] == RGBPixel::off());
+    assert!(strip.pixels[60] == RGBPixel::off());
+
+    // Per-pixel triples
+    strip.apply_command(&SerialCommand::from_payload(0x10, &[0x00, 0x00, 59, 1, 2, 3, 4, 5, 6])).unwrap();
+    assert_eq!(rgb(&strip.pixels[58]), (7, 8, 9));
+    assert_eq!(rgb(&strip.pixels[59]), (1, 2, 3));
+    assert_eq!(rgb(&strip.pixels[60]), (4, 5, 6));
+
+    // Each format needs its own minimum length
+    assert!(!SerialCommand::from_payload(0x10, &[0x01, 0x00, 10, 0x00, 50, 7, 8]).validate_length_with_action());
+    assert!(!SerialCommand::from_payload(0x10, &[0x00, 0x00, 10, 1, 2]).validate_length_with_action());
+  }
+
+  #[test]
+  fn wrapped_pixels_and_shift_cross_the_ends() {
+    let n = NUM_LEDS as i32;
+    let mut strip = LEDStrip::new();
+    strip.set_pixel_wrapped(-1, RGBPixel::red());
+    strip.set_pixel_wrapped(n, RGBPixel::green());
+    strip.set_pixel_wrapped(-n - 2, RGBPixel::blue());
+    assert!(matches!(strip.setting, StripSetting::Custom));
+    assert!(strip.pixels[NUM_LEDS - 1] == RGBPixel::red());
+    assert!(strip.pixels[0] == RGBPixel::green());
+    assert!(strip.pixels[NUM_LEDS - 2] == RGBPixel::blue());
+
+    // The last LEDs rotate around to the start, and back again
+    strip.shift(2);
+    assert!(strip.pixels[0] == RGBPixel::blue());
+    assert!(strip.pixels[1] == RGBPixel::red());
+    assert!(strip.pixels[2] == RGBPixel::green());
+    strip.shift(-2 - n);
+    assert!(strip.pixels[NUM_LEDS - 2] == RGBPixel::blue());
+    assert!(strip.pixels[NUM_LEDS - 1] == RGBPixel::red());
+    assert!(strip.pixels[0] == RGBPixel::green());
+  }
+
+  #[test]
+  fn keepalive_changes_nothing() {
+    let mut strip = configured_strip();
+    set_setting(&mut strip, &[0x02, 10, 20, 30, 255]).unwrap();
+    strip.update_pixels();
+    let config = strip.config_snapshot();
+    let pixels = strip.pixels;
+    // Any payload is ignored
+    for payload in [&[][..], &[0x01, 0x02]] {
+      let keepalive = SerialCommand::from_payload(0x00, payload);
+      assert!(keepalive.validate_length_with_action());
+      assert_eq!(strip.apply_command(&keepalive), Ok(()));
+    }
+    assert!(strip.config_snapshot() == config);
+    assert!(strip.pixels == pixels);
+    assert!(matches!(strip.setting, StripSetting::SolidColor { r: 10, g: 20, b: 30 }));
+    assert!(strip.is_on);
+    assert!(!strip.update_pixels());
+  }
+
+  #[test]
+  fn chase_direction_reverses_the_lit_leds() {
+    let mut strip = LEDStrip::new();
+    // RainbowChase with every 4th LED lit, moving one LED per frame
+    set_setting(&mut strip, &[0x04, 0x3F, 0x80, 0x00, 0x00, 4]).unwrap();
+    strip.set_phase_step(0.25);
+    let lit = |strip: &mut LEDStrip| {
+      strip.update_pixels();
+      strip.pixels[..4].iter().position(|&pixel| pixel != RGBPixel::off()).unwrap()
+    };
+    let forward: [usize; 5] = core::array::from_fn(|_| lit(&mut strip));
+    for pair in forward.windows(2) {
+      assert_eq!(pair[1], (pair[0] + 1) % 4);
+    }
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x18, 0x01, 0x00, 0x00])).unwrap();
+    let backward: [usize; 5] = core::array::from_fn(|_| lit(&mut strip));
+    for pair in backward.windows(2) {
+      assert_eq!(pair[1], (pair[0] + 3) % 4);
+    }
+    // Turning around doesn't jump, the chase turns at the frame forward would have drawn next
+    assert_eq!(backward[0], (forward[4] + 1) % 4);
+
+    // The start offset moves the lit LEDs without changing direction
+    let before = lit(&mut strip);
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x18, 0x01, 0x00, 0x01])).unwrap();
+    assert_eq!(lit(&mut strip), (before + 3 + 1) % 4);
+  }
+
+  #[test]
+  fn chip_timing_presets_change_the_pulses() {
+    let presets = [ChipTiming::Ws2812b, ChipTiming::Ws2811, ChipTiming::Ws2815];
+    let mut frames = [[Pulse::default(); 24]; 3];
+    for (id, frame) in frames.iter_mut().enumerate() {
+      let mut strip = LEDStrip::new();
+      strip.apply_command(&SerialCommand::from_payload(0x02, &[0x1B, id as u8])).unwrap();
+      assert!(strip.chip_timing == presets[id]);
+      // A pixel with both 0 and 1 bits
+      strip.pixels[0] = RGBPixel::new(0x0F, 0xF0, 0x55);
+      strip.generate_pulse_data();
+      let mut buffer = [Pulse::default(); PULSE_BUFFER_LEN];
+      frame.copy_from_slice(&strip.get_pulse_data(&mut buffer)[..24]);
+      // A 1 stays high longer than a 0, and a bit lasts about 1.25us, twice that on the 400kHz WS2811
+      let (t0h, t0l, t1h, t1l) = presets[id].ticks();
+      assert!(t1h > t0h);
+      let bit_ns = |high: u16, low: u16| (high + low) as u32 * 1000 / RMT_TICKS_PER_US;
+      let expected_ns = if presets[id] == ChipTiming::Ws2811 { 2500 } else { 1250 };
+      for ns in [bit_ns(t0h, t0l), bit_ns(t1h, t1l)] {
+        assert!(ns.abs_diff(expected_ns) <= 150, "{ns}");
+      }
+    }
+    assert!(frames[0] != frames[1] && frames[1] != frames[2] && frames[0] != frames[2]);
+    let mut strip = LEDStrip::new();
+    let unknown = SerialCommand::from_payload(0x02, &[0x1B, 0x03]);
+    assert_eq!(strip.apply_command(&unknown), Err(CommandError::UnknownOption(0x03)));
+  }
+
+  #[test]
+  fn freezing_holds_the_phase_and_frame() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    strip.set_phase_step(0.01);
+    strip.update_pixels();
+    let phase = strip.get_phase();
+    let pixels = strip.pixels;
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x1C, 0x01])).unwrap();
+    assert!(strip.is_frozen());
+    // The frame after any command is sent again, after that nothing changes
+    strip.update_pixels();
+    assert_eq!(strip.get_phase(), phase);
+    for _ in 0..5 {
+      assert!(!strip.update_pixels());
+      assert_eq!(strip.get_phase(), phase);
+    }
+    assert!(strip.pixels == pixels);
+    assert!(matches!(strip.setting, StripSetting::RainbowCycle { .. }));
+    // Unfreezing carries on from the same phase
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x1C, 0x00])).unwrap();
+    assert!(strip.update_pixels());
+    assert!((strip.get_phase() - (phase + 0.01)).abs() < 1e-6);
+  }
+
+  #[test]
+  fn mask_of_zero_blacks_out_only_the_masked_leds() {
+    let render = |strip: &mut LEDStrip| {
+      strip.update_pixels();
+      strip.generate_pulse_data();
+      let mut buffer = [Pulse::default(); PULSE_BUFFER_LEN];
+      strip.get_pulse_data(&mut buffer);
+      buffer
+    };
+    fn led(frame: &[Pulse], i: usize) -> &[Pulse] {
+      &frame[i * 24..(i + 1) * 24]
+    }
+    let mut plain = LEDStrip::new();
+    set_setting(&mut plain, &[0x02, 200, 100, 50, 255]).unwrap();
+    let plain = render(&mut plain);
+    let black = render(&mut LEDStrip::new());
+
+    let mut masked = LEDStrip::new();
+    set_setting(&mut masked, &[0x02, 200, 100, 50, 255]).unwrap();
+    masked.apply_command(&SerialCommand::from_payload(0x13, &[0x00, 10, 0, 0, 0, 128])).unwrap();
+    let masked = render(&mut masked);
+    for i in 10..13 {
+      assert_eq!(led(&masked, i), led(&black, i));
+    }
+    // Half a mask dims without blacking out
+    assert!(led(&masked, 13) != led(&plain, 13) && led(&masked, 13) != led(&black, 13));
+    for i in [0, 9, 14, NUM_LEDS - 1] {
+      assert_eq!(led(&masked, i), led(&plain, i));
+    }
+  }
+
+  #[test]
+  fn hue_gradient_takes_the_short_way_around() {
+    for (start, end) in [(350u16, 10u16), (10, 350)] {
+      let mut strip = LEDStrip::new();
+      strip.set_brightness(1.0);
+      let (s, e) = (start.to_be_bytes(), end.to_be_bytes());
+      set_setting(&mut strip, &[0x10, s[0], s[1], e[0], e[1], 255, 255]).unwrap();
+      strip.update_pixels();
+      assert!(strip.pixels[0] == hsv_to_rgb(start, 255, 255));
+      assert!(strip.pixels[NUM_LEDS - 1] == hsv_to_rgb(end, 255, 255));
+      // Through red (0 degrees), never through green or blue
+      assert!(strip.pixels[NUM_LEDS / 2] == hsv_to_rgb(0, 255, 255));
+      assert!(strip.pixels.iter().all(|pixel| pixel.r == 255 && pixel.g < 50 && pixel.b < 50));
+    }
+  }
+
+  #[test]
+  fn refresh_sends_a_frame_without_changes() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x02, 10, 20, 30, 255]).unwrap();
+    strip.update_pixels();
+    // Settled, render() would skip sending
+    assert!(!strip.update_pixels());
+    assert!(!strip.take_refresh());
+
+    strip.request_refresh();
+    let pixels = strip.pixels;
+    assert!(!strip.update_pixels());
+    assert!(strip.pixels == pixels);
+    // render() sends when either is true, and only once
+    assert!(strip.take_refresh());
+    assert!(!strip.take_refresh());
+
+    strip.apply_command(&SerialCommand::from_payload(0x14, &[])).unwrap();
+    assert!(strip.take_refresh());
+  }
+
+  #[test]
+  fn rejected_commands_leave_pixels_unchanged() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x02, 10, 20, 30, 255]).unwrap();
+    strip.update_pixels();
+    let pixels = strip.pixels;
+    let past_end = (NUM_LEDS as u16).to_be_bytes();
+    let manual = SerialCommand::from_payload(0x04, &[past_end[0], past_end[1], 1, 2, 3]);
+    assert_eq!(strip.try_apply_command(&manual), Err(CommandError::IndexOutOfRange(NUM_LEDS)));
+    // A valid manual write followed by a bad one in a compound command doesn't apply either
+    let bad_compound = compound(&[(0x04, &[0x00, 0x00, 1, 2, 3]), (0x04, &[past_end[0], past_end[1], 1, 2, 3])]);
+    assert_eq!(strip.apply_command(&bad_compound), Err(CommandError::IndexOutOfRange(NUM_LEDS)));
+    assert!(strip.pixels == pixels);
+    assert!(matches!(strip.setting, StripSetting::SolidColor { .. }));
+  }
+
+  #[test]
+  fn perceptual_brightness_curve() {
+    // Half as bright looking is well under half the power
+    let half = perceptual_to_linear(0.5);
+    assert!((half - 0.184).abs() < 0.001, "{half}");
+    assert_eq!(perceptual_to_linear(0.0), 0.0);
+    assert!((perceptual_to_linear(1.0) - 1.0).abs() < 1e-6);
+    // Both pieces of the curve meet
+    assert!((perceptual_to_linear(0.08) - perceptual_to_linear(0.080_01)).abs() < 1e-4);
+
+    let mut strip = LEDStrip::new();
+    strip.set_brightness(0.5);
+    assert_eq!(strip.brightness_factor, 0.5);
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x1E, 0x01])).unwrap();
+    assert_eq!(strip.brightness_factor, half);
+    assert_eq!(strip.get_brightness(), 0.5);
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x1E, 0x00])).unwrap();
+    assert_eq!(strip.brightness_factor, 0.5);
+  }
+
+  #[test]
+  fn pixel_lerp_endpoints_and_midpoint() {
+    let a = RGBPixel::new(10, 200, 33);
+    let b = RGBPixel::new(250, 0, 77);
+    assert_eq!(rgb(&a.lerp(&b, 0.0)), (10, 200, 33));
+    assert_eq!(rgb(&a.lerp(&b, 1.0)), (250, 0, 77));
+    // Halves round up
+    assert_eq!(rgb(&RGBPixel::off().lerp(&RGBPixel::white(), 0.5)), (128, 128, 128));
+    // t is clamped
+    assert_eq!(rgb(&a.lerp(&b, -1.0)), (10, 200, 33));
+    assert_eq!(rgb(&a.lerp(&b, 2.0)), (250, 0, 77));
+    assert_eq!(rgb(&a.saturating_sub(&RGBPixel::new(20, 100, 33))), (0, 100, 0));
+  }
+
+  #[test]
+  fn status_reply_carries_the_phase() {
+    let mut strip = LEDStrip::new();
+    set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+    strip.set_phase_step(0.0123);
+    for _ in 0..7 {
+      strip.update_pixels();
+    }
+    let status = Status { dropped_bytes: 1, frame_count: strip.get_frame_count(), uptime_ms: 2, phase: strip.get_phase() };
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    let len = strip.encode_response(&SerialCommand::from_payload(0x08, &[]), &status, &mut buffer);
+    assert_eq!(len, 4 + 24 + 2);
+    let phase = f32::from_be_bytes([buffer[24], buffer[25], buffer[26], buffer[27]]);
+    assert_eq!(phase.to_bits(), strip.phase.to_bits());
+    assert!((phase - 7.0 * 0.0123).abs() < 1e-6);
+  }
+
+  #[test]
+  fn white_balance_warms_white_but_not_red() {
+    let mut strip = LEDStrip::new();
+    strip.set_brightness(1.0);
+    strip.apply_command(&SerialCommand::from_payload(0x02, &[0x21, 255, 240, 180])).unwrap();
+    set_setting(&mut strip, &[0x02, 255, 255, 255, 255]).unwrap();
+    strip.update_pixels();
+    // Less blue than red, so white looks warmer
+    assert_eq!(rgb(&strip.pixels[0]), (255, 240, 180));
+    // Only the part the channels share is balanced
+    set_setting(&mut strip, &[0x02, 255, 100, 50, 255]).unwrap();
+    strip.update_pixels();
+    assert_eq!(rgb(&strip.pixels[0]), (255, 97, 35));
+    set_setting(&mut strip, &[0x02, 255, 0, 0, 255]).unwrap();
+    strip.update_pixels();
+    assert_eq!(rgb(&strip.pixels[0]), (255, 0, 0));
+  }
+
+  #[test]
+  fn rotate_command_wraps_both_ways() {
+    let rotate = |offset: i16| SerialCommand::from_payload(0x15, &offset.to_be_bytes());
+    let mut strip = LEDStrip::new();
+    let pattern = |i: usize| RGBPixel::new(i as u8, (i >> 8) as u8, 7);
+    for (i, pixel) in strip.pixels.iter_mut().enumerate() {
+      *pixel = pattern(i);
+    }
+    strip.apply_command(&rotate(3)).unwrap();
+    assert!(matches!(strip.setting, StripSetting::Custom));
+    // The last 3 LEDs come around to the start
+    for i in 0..NUM_LEDS {
+      assert!(strip.pixels[i] == pattern((i + NUM_LEDS - 3) % NUM_LEDS));
+    }
+    strip.apply_command(&rotate(-3)).unwrap();
+    assert!((0..NUM_LEDS).all(|i| strip.pixels[i] == pattern(i)));
+    // The first 3 go around to the end
+    strip.apply_command(&rotate(-3)).unwrap();
+    assert!(strip.pixels[NUM_LEDS - 3] == pattern(0));
+    assert!(strip.pixels[NUM_LEDS - 1] == pattern(2));
+    assert!(strip.pixels[0] == pattern(3));
+    // Rotated pixels stick, Custom doesn't redraw them
+    strip.update_pixels();
+    assert!(strip.pixels[0] == pattern(3));
+    assert!(!SerialCommand::from_payload(0x15, &[0x00]).validate_length_with_action());
+  }
+
+  #[test]
+  fn three_stop_gradient_hits_each_stop() {
+    let mut strip = LEDStrip::new();
+    strip.set_brightness(1.0);
+    // Stops at the start, a third of the way along and the end
+    set_setting(&mut strip, &[0x12, 3, 0, 255, 0, 0, 85, 0, 255, 0, 255, 0, 0, 255]).unwrap();
+    strip.update_pixels();
+    assert_eq!(rgb(&strip.pixels[0]), (255, 0, 0));
+    assert_eq!(rgb(&strip.pixels[(NUM_LEDS - 1) / 3]), (0, 255, 0));
+    assert_eq!(rgb(&strip.pixels[NUM_LEDS - 1]), (0, 0, 255));
+    // Between stops it's a blend of the two
+    let (r, g, b) = rgb(&strip.pixels[(NUM_LEDS - 1) / 6]);
+    assert!(r > 0 && g > 0 && b == 0);
+    // Stops have to be in order
+    assert!(set_setting(&mut strip, &[0x12, 2, 200, 255, 0, 0, 100, 0, 255, 0]).is_err());
+  }
+
+  #[test]
+  fn rainbow_speed_modulation_varies_the_phase_step() {
+    let steps = |modulation: &[u8]| {
+      let mut strip = LEDStrip::new();
+      set_setting(&mut strip, &[0x03, 0x3F, 0x80, 0x00, 0x00]).unwrap();
+      strip.set_phase_step(0.01);
+      strip.apply_command(&SerialCommand::from_payload(0x02, modulation)).unwrap();
+      let mut steps = [0.0; 8];
+      for step in steps.iter_mut() {
+        let before = strip.get_phase();
+        strip.update_pixels();
+        *step = strip.get_phase() - before;
+      }
+      steps
+    };
+    // Full depth over 8 frames: from the normal step up to double, back, down to standing still
+    let modulated = steps(&[0x22, 255, 0x00, 8]);
+    for (frame, expected) in [(0, 0.01), (2, 0.02), (4, 0.01), (6, 0.0)] {
+      assert!((modulated[frame] - expected).abs() < 1e-4, "{modulated:?}");
+    }
+    // Same average speed over a whole period
+    assert!((modulated.iter().sum::<f32>() - 0.08).abs() < 1e-4);
+    // A depth of 0 turns it off
+    let steady = steps(&[0x22, 0, 0x00, 8]);
+    assert!(steady.iter().all(|step| (step - 0.01).abs() < 1e-6));
+  }
+
+  #[test]
+  fn set_if_changed_reports_only_real_changes() {
+    let mut pixel = RGBPixel::new(1, 2, 3);
+    assert!(!pixel.set_if_changed(RGBPixel::new(1, 2, 3)));
+    assert_eq!(rgb(&pixel), (1, 2, 3));
+    // Any one channel counts
+    for new in [RGBPixel::new(9, 2, 3), RGBPixel::new(9, 8, 3), RGBPixel::new(9, 8, 7)] {
+      assert!(pixel.set_if_changed(new));
+      assert!(pixel == new);
+    }
+    assert!(!pixel.set_if_changed(RGBPixel::new(9, 8, 7)));
+    assert!(pixel != RGBPixel::new(9, 8, 6));
+  }
+}
@@ -166,7 +166,7 @@ class LEDController:
         Set strip setting (animation/pattern mode).
 
         Args:
-            setting_id: Setting ID (0x00=Custom, 0x01=Breathing, 0x02=SolidColor, 0x03=RainbowCycle)
+            setting_id: Setting ID (0x0D=Custom, 0x01=Breathing, 0x02=SolidColor, 0x03=RainbowCycle)
             r: Red value for Breathing/SolidColor (0-255)
             g: Green value for Breathing/SolidColor (0-255)
             b: Blue value for Breathing/SolidColor (0-255)
@@ -190,7 +190,7 @@ class LEDController:
                 raise ValueError("RainbowCycle requires cycles value")
             payload += struct.pack('>f', cycles)
             description = f"RainbowCycle(cycles={cycles})"
-        elif setting_id == 0x00:
+        elif setting_id == 0x0D:
             description = "Custom"
         else:
             raise ValueError(f"Invalid setting_id: {setting_id}")
@@ -314,7 +314,7 @@ def main():
                 except ValueError:
                     print("Error: Invalid brightness value")
             elif choice == '4':
-                controller.set_strip_setting(0x00)  # Custom
+                controller.set_strip_setting(0x0D)  # Custom
             elif choice == '5':
                 try:
                     r = int(input("Enter red (0-255): ").strip())